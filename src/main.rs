@@ -1,10 +1,61 @@
-use std::{path::PathBuf, fs, process::{Command, ExitStatus}, path::Path, env, collections::HashMap, ffi::OsStr};
+use std::{cell::RefCell, os::unix::{ffi::OsStrExt, fs::{MetadataExt, PermissionsExt}, process::ExitStatusExt}, path::PathBuf, fs, process::{Command, Child, ExitCode, ExitStatus, Stdio}, path::Path, env, collections::{HashMap, HashSet}, io::{BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write}, ffi::{OsStr, OsString}, sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver, RecvTimeoutError, SyncSender}, Arc, Condvar, Mutex, OnceLock}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use anyhow::{Result, anyhow};
 use dirs::home_dir;
 use clap::{Parser, crate_name};
 use serde::{Deserialize, Serialize};
 use blake3::Hasher;
 use junk_file::is_junk;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use regex::Regex;
+
+// Exit codes for scripting, 1 is left for any other error
+const EXIT_FILE_FAILURES: u8 = 2;
+const EXIT_CONFIG_ERROR: u8 = 3;
+const EXIT_MISSING_DEPENDENCY: u8 = 4;
+const EXIT_INTERRUPTED: u8 = 130;
+
+// Set when a running encode should be killed at that time, checked while waiting for child processes
+static INTERRUPT_DEADLINE: OnceLock<Instant> = OnceLock::new();
+
+// Set by the SIGINT/SIGTERM handler, forwarded to running child processes by wait_child
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Set from max_concurrent_probes, bounds ffprobe processes across all workers
+static PROBE_LIMIT: OnceLock<ProcessLimit> = OnceLock::new();
+
+// Set on the first NVENC encode from max_gpu_sessions or the detected driver limit,
+// bounds the NVENC encodes on each CUDA device, keyed like CUDA_DEVICE
+static GPU_SESSION_LIMITS: OnceLock<HashMap<Option<u32>, ProcessLimit>> = OnceLock::new();
+
+// Set from history_db for the all subcommand, shared by encode and save workers
+static HISTORY_DB: OnceLock<HistoryDb> = OnceLock::new();
+
+// Set from review_list for the all subcommand, shared by workers adding to it
+static REVIEW_LIST: OnceLock<ReviewList> = OnceLock::new();
+
+// Set from reference_sizes for the all subcommand, sizes of the originals sources were transcoded from
+static REFERENCE_SIZES: OnceLock<HashMap<PathBuf, u64>> = OnceLock::new();
+
+// Set when parallel workers print to a terminal, so their progress bars stack instead of overwriting each other
+static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+thread_local! {
+    // e.g. "[file 3/100]" while a parallel worker handles a file, prefixed to what it reports
+    static FILE_LABEL: RefCell<Option<String>> = const { RefCell::new(None) };
+    // with --emit-scripts, where the encode commands for the file a worker handles are written
+    static ENCODE_SCRIPT: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    // from cuda_devices, the GPU the encodes of this worker run on
+    static CUDA_DEVICE: RefCell<Option<u32>> = const { RefCell::new(None) };
+    // with debug-single --log-file, where the full ab-av1 output is saved
+    static AB_AV1_LOG: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+// println! for workers, lines of parallel encodes tell which file they are about
+macro_rules! report {
+    ($($arg:tt)*) => {
+        report_line(format!($($arg)*))
+    };
+}
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
@@ -12,8 +63,14 @@ enum Error {
     InvalidVideoPath(PathBuf),
     #[error("Failed to execute ab-av1 command: {0}")]
     AbAv1CommandFailed(ExitStatus),
+    #[error("Target VMAF unreachable within crf bounds: {0}")]
+    VmafTargetUnreachable(ExitStatus),
+    #[error("No crf keeps the encode under max_encoded_percent: {0}")]
+    MaxEncodedPercentExceeded(ExitStatus),
     #[error("Failed to execute force crf ffmpeg command: {0}")]
     ForceCrfFfmpegCommandFailed(ExitStatus),
+    #[error("ab-av1 crf-search didn't report a crf: {0}")]
+    CrfSearchWithoutCrf(PathBuf),
     #[error("Conflict encoding video path {1:?} for video {0:?}")]
     ConflictVideoEncoding(PathBuf, PathBuf),
     #[error("Conflict failed copy path {1:?} for video {0:?}")]
@@ -30,9 +87,94 @@ enum Error {
     ParseDurationSecondsFailed(String),
     #[error("Found invalid video file in saved path: {0}")]
     FoundInvalidVideoFileInSavedPath(PathBuf),
+    #[error("Failed to execute ffprobe probe video: {0}")]
+    FfprobeProbeVideoFailed(String),
+    #[error("Unsupported encoder: {0}")]
+    UnsupportedEncoder(String),
+    #[error("Container {1:?} does not support codec of encoder {0:?}")]
+    UnsupportedContainerCodec(String, String),
+    #[error("Container {1:?} does not support audio codec {0:?}")]
+    UnsupportedContainerAudioCodec(String, String),
+    #[error("Audio encoder {0:?} is not available in ffmpeg, see ffmpeg -encoders")]
+    AudioEncoderNotAvailable(String),
+    #[error("Failed to execute ffmpeg decode check: {0}")]
+    FfmpegDecodeCheckFailed(String),
+    #[error("Max runtime exceeded")]
+    MaxRuntimeExceeded,
+    #[error("{0} files failed in a row, something is likely broken for every file (GPU driver, disk, ffmpeg), aborting")]
+    TooManyConsecutiveFailures(u32),
+    #[error("Missing dependency, command not found: {0}")]
+    MissingDependency(String),
+    #[error("Interrupted by signal")]
+    Interrupted,
+    #[error("Refused to write into save directory in readonly mode: {0}")]
+    ReadonlySaveDir(PathBuf),
+    #[error("Save directory can't be created, permission denied: {0}")]
+    SaveDirNotWritable(PathBuf),
+    #[error("Invalid sidecar {0:?}: {1}")]
+    InvalidSidecar(PathBuf, String),
+    #[error("Failed to execute ffprobe show chapters: {0}")]
+    FfprobeShowChaptersFailed(String),
+    #[error("Chapter durations {1:.2} sec don't match source duration {2:.2} sec: {0}")]
+    ChapterDurationMismatch(PathBuf, f64, f64),
+    #[error("Failed to execute ffmpeg quality metrics: {0}")]
+    FfmpegQualityMetricsFailed(String),
+    #[error("Failed to execute renamer command: {0}")]
+    RenamerCommandFailed(String),
+    #[error("Renamer output {0:?} doesn't end with extension {1:?}")]
+    RenamerInvalidExtension(String, String),
+    #[error("Failed to execute ffmpeg complexity probe: {0}")]
+    FfmpegComplexityProbeFailed(String),
+    #[error("Encoded output has zero duration: {0}")]
+    ZeroDurationOutput(PathBuf),
+    #[error("Failed to execute target policy hook: {0}")]
+    TargetPolicyHookFailed(String),
+    #[error("Renamer command not found or not executable: {0}")]
+    RenamerCommandNotFound(String),
+    #[error("Invalid HLS playlist {0:?}: {1}")]
+    InvalidHlsPlaylist(PathBuf, String),
+    #[error("No frames match the image sequence pattern: {0}")]
+    ImageSequenceNoFrames(String),
+    #[error("Frame {0:?} is {1}x{2}, but the sequence starts at {3}x{4}")]
+    ImageSequenceInconsistentSize(PathBuf, u32, u32, u32, u32),
+    #[error("Checksum mismatch after copying {0:?} to {1:?}")]
+    SaveChecksumMismatch(PathBuf, PathBuf),
+    #[error("Failed to execute preview ffmpeg command: {0}")]
+    PreviewFfmpegCommandFailed(ExitStatus),
+    #[error("Failed to generate self test clip: {0}")]
+    SelfTestClipFailed(String),
+    #[error("Self test failed: {0} of {1} checks")]
+    SelfTestFailed(usize, usize),
+    #[error("Encoded output is implausibly small ({1} bytes, expected at least {2}): {0}")]
+    TinyOutput(PathBuf, u64, u64),
+    #[error("history_db is not set in the config")]
+    HistoryDbNotConfigured,
+    #[error("review_list is not set in the config")]
+    ReviewListNotConfigured,
+    #[error("Output frame rate {1} differs from source {2}: {0}")]
+    FrameRateMismatch(PathBuf, f64, f64),
+    #[error("Invalid profiles {0:?}: {1}")]
+    InvalidProfiles(PathBuf, String),
+    #[error("Invalid reference sizes {0:?}: {1}")]
+    InvalidReferenceSizes(PathBuf, String),
+    #[error("Invalid catalog {0:?}: {1}")]
+    InvalidCatalog(PathBuf, String),
+    #[error("Program {1} not found in video: {0}")]
+    ProgramNotFound(PathBuf, u32),
+    #[error("No {2} stream at index {1} in video: {0}")]
+    StreamNotFound(PathBuf, usize, String),
+    #[error("Invalid aspect ratio {0:?}, expected like \"16:9\"")]
+    InvalidAspectRatio(String),
+    #[error("Stream selection only applies to ffmpeg encodes, set use_ab_av1_crf_with_ffmpeg to use it with ab-av1")]
+    StreamSelectionWithoutFfmpeg,
+    #[error("Invalid multipart_pattern {0:?}: {1}")]
+    InvalidMultipartPattern(String, String),
+    #[error("Failed to execute concat ffmpeg command: {0}")]
+    ConcatFfmpegCommandFailed(ExitStatus),
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
 struct Config {
     save_dir: PathBuf,
     tmp_dir: PathBuf,
@@ -43,6 +185,133 @@ struct Config {
     move_failed_files: bool,
     delete_almost_same_files: bool,
     save_dir_overrides: HashMap<String, PathBuf>,
+    renditions: Vec<RenditionConfig>,
+    max_height: Option<u32>,
+    continue_on_fatal: bool,
+    // a save directory without permission skips only the files going there, unless this is set
+    abort_on_unwritable_save_dir: bool,
+    encoder: String,
+    output_extension: String,
+    output_extension_by_input: HashMap<String, String>,
+    status_file: Option<PathBuf>,
+    status_interval_secs: Option<u64>,
+    verify_before_delete: bool,
+    encoding_subdir: String,
+    sample_subdir: String,
+    // leaves each ab-av1 call's samples in sample_subdir for debugging, see SampleDir
+    keep_sample_files: bool,
+    encoding_tmp_dir: Option<PathBuf>,
+    sample_tmp_dir: Option<PathBuf>,
+    dedupe_dirs: Vec<PathBuf>,
+    audio_tracks: Vec<AudioTrackConfig>,
+    // for the audio of sources without audio_tracks, e.g. "libfdk_aac" or "libopus"
+    audio_codec: String,
+    force_crf_progress: bool,
+    skip_if_source_bitrate_below: Option<u64>,
+    // display aspect ratios to encode, e.g. ["16:9", "4:3"], anything else is skipped for review by hand
+    allowed_aspect_ratios: Vec<String>,
+    // relative, so that 1920x1080 cropped to 1920x1072 still counts as 16:9
+    aspect_ratio_tolerance: f64,
+    log_file: Option<PathBuf>,
+    log_rotation: LogRotationConfig,
+    log_to_stderr: bool,
+    concurrency: Concurrency,
+    renamer: Option<RenamerConfig>,
+    encoder_subdir: bool,
+    min_acceptable_vmaf: Option<u8>,
+    readonly_save: bool,
+    split_by_chapters: bool,
+    preserve_xattrs: bool,
+    disable_max_percent_above_height: Option<u32>,
+    probesize: Option<String>,
+    analyzeduration: Option<String>,
+    // caps decoder and encoder threads of ffmpeg, ffprobe and ab-av1 on shared machines
+    ffmpeg_threads: Option<u32>,
+    verify_save_checksum: bool,
+    preserve_source_basename: bool,
+    max_gpu_temp_c: Option<u32>,
+    // for scheduled runs that yield to other GPU work, see gpu_busy
+    skip_if_gpu_busy: bool,
+    // content archived elsewhere, sources listed there are skipped, see Catalog
+    catalog: Option<PathBuf>,
+    expand_disc_images: bool,
+    output_prefix: Option<String>,
+    output_suffix: Option<String>,
+    max_source_decode_errors: Option<usize>,
+    // sources set aside for a human, skipped by every run until removed with the review command
+    review_list: Option<PathBuf>,
+    // which heuristics put a source on review_list, e.g. ["vmaf_unreachable", "aspect_ratio", "decode_errors"]
+    review_on: Vec<ReviewReason>,
+    // experimental, (low, high) VMAF target picked by how much the content moves
+    complexity_vmaf_band: Option<(u8, u8)>,
+    max_concurrent_probes: Option<usize>,
+    // concurrent NVENC encodes per card, consumer cards refuse sessions beyond the driver's limit,
+    // guessed from the GPU name and driver version when unset
+    max_gpu_sessions: Option<usize>,
+    extra_ffmpeg_output_args: Vec<String>,
+    control_file: Option<PathBuf>,
+    save_concurrency: usize,
+    target_policy_hook: Option<HookConfig>,
+    log_encode_speed: bool,
+    hls: Option<HlsConfig>,
+    skip_encoded_by_fingerprint: bool,
+    // skips sources with a saved output recorded in history_db that still exists, whatever it's named now
+    skip_encoded_by_history: bool,
+    // encodes with the crf history_db recorded for the unchanged source instead of searching again
+    reuse_recorded_crf: bool,
+    encoding_dir_on_save_fs: bool,
+    on_larger_output: LargerOutputAction,
+    on_max_encoded_percent_exceeded: MaxEncodedPercentAction,
+    // keyframes on scene cuts, see scene_cut_encoder_opts for what each encoder understands
+    scene_cut: Option<bool>,
+    scene_cut_threshold: Option<u32>,
+    // duty cycle, pauses batch_pause_secs after every batch_size encodes
+    batch_size: Option<usize>,
+    batch_pause_secs: Option<u64>,
+    // aborts the run after this many failed files in a row, any success starts the count over
+    max_consecutive_failures: Option<u32>,
+    rotation_mode: RotationMode,
+    preview: Option<PreviewConfig>,
+    quality_metrics_to_record: Vec<QualityMetric>,
+    record_source_path: bool,
+    // NVENC VBR floor against banding in dark scenes, e.g. "1M", can grow simple content
+    min_bitrate: Option<String>,
+    // finalized by another tool, see has_done_marker
+    done_marker: Option<String>,
+    write_done_marker: bool,
+    // ab-av1 rarely exits fine with a few KB output, anything smaller than either is a failed encode
+    min_output_bytes: Option<u64>,
+    min_output_ratio: Option<f64>,
+    // fail encodes whose avg_frame_rate differs from the source, they are only warned about otherwise
+    verify_fps: bool,
+    history_db: Option<PathBuf>,
+    // JSON object of source path to the size of its true original, e.g. {"/videos/a.mp4": 8123456789},
+    // for savings of sources that are already a transcode
+    reference_sizes: Option<PathBuf>,
+    denoise: Option<DenoiseConfig>,
+    // ab-av1 only searches the crf, the final encode runs through exec_force_crf_ffmpeg with all its flags
+    use_ab_av1_crf_with_ffmpeg: bool,
+    // AV1 grain synthesis, the encoder removes grain and the decoder adds it back, libsvtav1 and libaom-av1 only
+    film_grain: Option<FilmGrainConfig>,
+    // which program of a multi-program transport stream to encode, ffmpeg mixes streams of several otherwise
+    program: Option<ProgramSelection>,
+    // absolute stream indexes as in ffprobe, for the odd file whose default streams are wrong
+    video_stream: Option<usize>,
+    audio_stream: Option<usize>,
+    // cores per worker for parallel software encodes, e.g. [[0, 1, 2, 3], [4, 5, 6, 7]], workers beyond wrap around
+    cpu_affinity: Vec<Vec<usize>>,
+    // GPUs handed out to workers in turn for NVENC encodes, e.g. [0, 1]
+    cuda_devices: Vec<u32>,
+    // joins split archives like "movie.part1.mkv" and "movie.part2.mkv" into one encode named "movie.mkv"
+    merge_multipart: bool,
+    // the first group is the part number, the whole match is cut out of the file name to get the base name
+    multipart_pattern: String,
+    // set per file, tags written into the output, e.g. SOURCE_PATH for record_source_path
+    #[serde(skip)]
+    output_metadata: Vec<(String, String)>,
+    // set per file, the parts a merged source was concatenated from, removed along with it
+    #[serde(skip)]
+    multipart_sources: Vec<PathBuf>,
 }
 
 impl Default for Config {
@@ -58,390 +327,5770 @@ impl Default for Config {
             move_failed_files: false,
             delete_almost_same_files: false,
             save_dir_overrides: HashMap::new(),
+            renditions: Vec::new(),
+            max_height: None,
+            continue_on_fatal: false,
+            abort_on_unwritable_save_dir: false,
+            encoder: "av1_nvenc".to_string(),
+            output_extension: "mkv".to_string(),
+            output_extension_by_input: HashMap::new(),
+            status_file: None,
+            status_interval_secs: None,
+            verify_before_delete: false,
+            encoding_subdir: "encoding".to_string(),
+            sample_subdir: "ab_av1_tmp".to_string(),
+            keep_sample_files: false,
+            encoding_tmp_dir: None,
+            sample_tmp_dir: None,
+            dedupe_dirs: Vec::new(),
+            audio_tracks: Vec::new(),
+            audio_codec: "aac".to_string(),
+            force_crf_progress: false,
+            skip_if_source_bitrate_below: None,
+            allowed_aspect_ratios: Vec::new(),
+            aspect_ratio_tolerance: 0.02,
+            log_file: None,
+            log_rotation: LogRotationConfig::default(),
+            log_to_stderr: true,
+            concurrency: Concurrency::Fixed(1),
+            renamer: None,
+            encoder_subdir: false,
+            min_acceptable_vmaf: None,
+            readonly_save: false,
+            split_by_chapters: false,
+            preserve_xattrs: false,
+            disable_max_percent_above_height: None,
+            probesize: None,
+            analyzeduration: None,
+            ffmpeg_threads: None,
+            verify_save_checksum: false,
+            preserve_source_basename: false,
+            max_gpu_temp_c: None,
+            skip_if_gpu_busy: false,
+            catalog: None,
+            expand_disc_images: false,
+            output_prefix: None,
+            output_suffix: None,
+            max_source_decode_errors: None,
+            complexity_vmaf_band: None,
+            max_concurrent_probes: None,
+            max_gpu_sessions: None,
+            extra_ffmpeg_output_args: Vec::new(),
+            control_file: None,
+            save_concurrency: 0,
+            target_policy_hook: None,
+            log_encode_speed: false,
+            hls: None,
+            skip_encoded_by_fingerprint: false,
+            skip_encoded_by_history: false,
+            review_list: None,
+            review_on: Vec::new(),
+            reuse_recorded_crf: false,
+            encoding_dir_on_save_fs: false,
+            on_larger_output: LargerOutputAction::Keep,
+            on_max_encoded_percent_exceeded: MaxEncodedPercentAction::Keep,
+            scene_cut: None,
+            scene_cut_threshold: None,
+            batch_size: None,
+            max_consecutive_failures: None,
+            batch_pause_secs: None,
+            rotation_mode: RotationMode::Bake,
+            preview: None,
+            quality_metrics_to_record: Vec::new(),
+            record_source_path: false,
+            min_bitrate: None,
+            done_marker: None,
+            write_done_marker: false,
+            min_output_bytes: None,
+            min_output_ratio: None,
+            verify_fps: false,
+            history_db: None,
+            reference_sizes: None,
+            denoise: None,
+            use_ab_av1_crf_with_ffmpeg: false,
+            film_grain: None,
+            program: None,
+            video_stream: None,
+            audio_stream: None,
+            cpu_affinity: Vec::new(),
+            cuda_devices: Vec::new(),
+            merge_multipart: false,
+            multipart_pattern: r"\.part(\d+)".to_string(),
+            output_metadata: Vec::new(),
+            multipart_sources: Vec::new(),
         }
     }
 }
 
-#[derive(Parser, Debug)]
-struct Args {
-    #[clap(subcommand)]
-    subcmd: SubCommand,
-}
+impl Config {
+    fn validate(&self) -> Result<()> {
+        let codec = Codec::from_encoder(&self.encoder).ok_or(Error::UnsupportedEncoder(self.encoder.clone()))?;
+        for output_extension in std::iter::once(&self.output_extension).chain(self.output_extension_by_input.values()) {
+            if !codec.is_supported_by_container(output_extension) {
+                return Err(anyhow!(Error::UnsupportedContainerCodec(self.encoder.clone(), output_extension.clone())));
+            }
+            for audio_codec in self.audio_codecs() {
+                if !is_audio_codec_supported_by_container(audio_codec, output_extension) {
+                    return Err(anyhow!(Error::UnsupportedContainerAudioCodec(audio_codec.to_string(), output_extension.clone())));
+                }
+            }
+        }
+        if let Some(renamer) = &self.renamer {
+            if !is_executable_command(&renamer.command) {
+                return Err(anyhow!(Error::RenamerCommandNotFound(renamer.command.display().to_string())));
+            }
+        }
+        if !self.extra_ffmpeg_output_args.is_empty() {
+            log::warn!("Passing extra ffmpeg output args verbatim, they can break encodes: {:?}", self.extra_ffmpeg_output_args);
+        }
+        if (self.scene_cut.is_some() || self.scene_cut_threshold.is_some()) && scene_cut_encoder_opts(self).is_empty() {
+            log::warn!("Scene cut settings are not supported by {}, ignoring them", self.encoder);
+        }
+        if self.scene_cut_threshold.is_some() && self.encoder != "libx265" {
+            log::warn!("scene_cut_threshold only applies to libx265, ignoring it for {}", self.encoder);
+        }
+        if self.film_grain.is_some() && film_grain_encoder_opts(self).is_empty() {
+            log::warn!("film_grain only applies to libsvtav1 and libaom-av1, ignoring it for {}", self.encoder);
+        }
+        if self.min_bitrate.is_some() && !is_nvenc_encoder(&self.encoder) {
+            log::warn!("min_bitrate only applies to NVENC encoders, ignoring it for {}", self.encoder);
+        }
+        if self.audio_stream.is_some() && !self.audio_tracks.is_empty() {
+            log::warn!("audio_stream is ignored, as audio_tracks select the audio");
+        }
+        // ab-av1 maps the default streams itself, only a crf from a sidecar encodes through ffmpeg then
+        if (self.video_stream.is_some() || self.audio_stream.is_some()) && !self.use_ab_av1_crf_with_ffmpeg {
+            log::warn!("video_stream and audio_stream are ignored by ab-av1 encodes, set use_ab_av1_crf_with_ffmpeg to apply them to every encode");
+        }
+        for aspect_ratio in &self.allowed_aspect_ratios {
+            parse_aspect_ratio(aspect_ratio).ok_or(Error::InvalidAspectRatio(aspect_ratio.clone()))?;
+        }
+        if (self.skip_encoded_by_history || self.reuse_recorded_crf) && self.history_db.is_none() {
+            return Err(anyhow!(Error::HistoryDbNotConfigured));
+        }
+        if !self.review_on.is_empty() && self.review_list.is_none() {
+            return Err(anyhow!(Error::ReviewListNotConfigured));
+        }
+        if self.batch_size.is_some() != self.batch_pause_secs.is_some() {
+            log::warn!("batch_size and batch_pause_secs only work together, ignoring them");
+        }
+        Ok(())
+    }
 
-#[derive(Parser, Debug)]
-enum SubCommand {
-    All(AllOpts),
-    DebugSingle(DebugSingleOpts),
-    ForceCrfSingle(ForceCrfSingleOpts),
-}
+    // audio_codec only applies when audio_tracks don't say otherwise
+    fn audio_codecs(&self) -> Vec<&str> {
+        if self.audio_tracks.is_empty() {
+            vec![self.audio_codec.as_str()]
+        } else {
+            self.audio_tracks.iter().map(|audio_track| audio_track.codec.as_str()).collect()
+        }
+    }
 
-#[derive(Parser, Debug)]
-struct AllOpts {
-    video_dir: PathBuf,
-    target_vmaf: u8,
-}
+    // e.g. `output_extension_by_input = { avi = "mp4", wmv = "mp4" }`, input extensions match case-insensitively
+    fn output_extension_for(&self, video_path: impl AsRef<Path>) -> String {
+        let video_path = video_path.as_ref();
+        let input_extension = video_path.extension().map(|extension| extension.to_string_lossy().to_lowercase());
+        self.output_extension_by_input.iter()
+            .find(|(extension, _)| input_extension.as_deref().is_some_and(|input_extension| extension.to_lowercase() == input_extension))
+            .map(|(_, output_extension)| output_extension.clone())
+            .unwrap_or_else(|| self.output_extension.clone())
+    }
 
-#[derive(Parser, Debug)]
-struct DebugSingleOpts {
-    video_path: PathBuf,
-    target_vmaf: u8,
+    // encoded files and ab-av1 samples can live on different filesystems,
+    // each falls back to tmp_dir when not configured
+    fn encoding_video_dir(&self) -> PathBuf {
+        self.encoding_tmp_dir.as_ref().unwrap_or(&self.tmp_dir).join(&self.encoding_subdir)
+    }
+
+    // with encoding_dir_on_save_fs, encodes move to a hidden dir in save_dir when tmp_dir is on another
+    // filesystem, so the final rename never copies, while ab-av1 samples stay on the scratch disk
+    fn encoding_video_dir_for(&self, save_dir: impl AsRef<Path>) -> PathBuf {
+        let save_dir = save_dir.as_ref();
+        let encoding_video_dir = self.encoding_video_dir();
+        if !self.encoding_dir_on_save_fs || self.readonly_save {
+            return encoding_video_dir;
+        }
+        let encoding_base_dir = self.encoding_tmp_dir.as_ref().unwrap_or(&self.tmp_dir);
+        // save_dir may not exist yet, its nearest existing ancestor is on the same filesystem
+        let Some(existing_save_dir) = save_dir.ancestors().find(|dir| dir.exists()) else {
+            return encoding_video_dir;
+        };
+        match (fs::metadata(encoding_base_dir), fs::metadata(existing_save_dir)) {
+            (Ok(encoding_base_metadata), Ok(save_metadata)) if encoding_base_metadata.dev() != save_metadata.dev() => {
+                let encoding_video_dir = save_dir.join(format!(".{}", self.encoding_subdir));
+                log::debug!("Encoding dir on save filesystem: {:?}", encoding_video_dir);
+                encoding_video_dir
+            },
+            _ => encoding_video_dir,
+        }
+    }
+
+    fn sample_video_dir(&self) -> PathBuf {
+        self.sample_tmp_dir.as_ref().unwrap_or(&self.tmp_dir).join(&self.sample_subdir)
+    }
 }
 
-#[derive(Parser, Debug)]
-struct ForceCrfSingleOpts {
-    video_path: PathBuf,
-    crf: u8,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Av1,
+    Hevc,
+    Vp9,
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let config = jdt::project(crate_name!()).config::<Config>();
-    log::debug!("Config: {:?}", config);
+impl Codec {
+    fn from_encoder(encoder: &str) -> Option<Self> {
+        match encoder {
+            "av1_nvenc" | "libsvtav1" | "libaom-av1" | "librav1e" => Some(Self::Av1),
+            "hevc_nvenc" | "libx265" => Some(Self::Hevc),
+            "libvpx-vp9" => Some(Self::Vp9),
+            _ => None,
+        }
+    }
 
-    let args = Args::parse();
-    match args.subcmd {
-        SubCommand::All(opts) => run_all(opts, config)?,
-        SubCommand::DebugSingle(opts) => run_debug_single_command(opts, config)?,
-        SubCommand::ForceCrfSingle(opts) => run_force_crf_single_command(opts, config)?,
+    fn is_supported_by_container(&self, extension: &str) -> bool {
+        match extension.to_lowercase().as_str() {
+            "mkv" | "mp4" => true,
+            "webm" => matches!(self, Self::Av1 | Self::Vp9),
+            _ => false,
+        }
     }
+}
 
-    Ok(())
+// NVENC encoders take the tuned rate control flags and decode on the GPU,
+// software encoders get plain constant quality flags instead
+fn is_nvenc_encoder(encoder: &str) -> bool {
+    encoder.ends_with("_nvenc")
 }
 
-fn run_all(opts: AllOpts, config: Config) -> Result<()> {
-    let video_paths = jdt::walk_dir(&opts.video_dir, |path| path);
-    let encodnig_video_dir = config.tmp_dir.join("encoding");
-    let default_save_dir = &config.save_dir;
-    let save_dir_overrides = &config.save_dir_overrides;
+// Encoder private options for scene cut detection, as (option, value) pairs:
+// - NVENC: `no-scenecut`, on/off only
+// - libx265: `x265-params scenecut=`, off or a threshold
+// - libsvtav1: `svtav1-params scd=`, on/off only
+// - others: not supported
+fn scene_cut_encoder_opts(config: &Config) -> Vec<(&'static str, String)> {
+    let encoder = config.encoder.as_str();
+    if is_nvenc_encoder(encoder) {
+        config.scene_cut.map(|scene_cut| vec![("no-scenecut", if scene_cut { "0" } else { "1" }.to_string())]).unwrap_or_default()
+    } else if encoder == "libx265" {
+        match (config.scene_cut, config.scene_cut_threshold) {
+            (Some(false), _) => vec![("x265-params", "scenecut=0".to_string())],
+            (_, Some(threshold)) => vec![("x265-params", format!("scenecut={}", threshold))],
+            _ => Vec::new(),
+        }
+    } else if encoder == "libsvtav1" {
+        config.scene_cut.map(|scene_cut| vec![("svtav1-params", format!("scd={}", scene_cut as u8))]).unwrap_or_default()
+    } else {
+        Vec::new()
+    }
+}
 
-    let inherited_log_level = env::var("RUST_LOG").unwrap_or("warn".to_string());
-    log::debug!("Inherited log level: {}", inherited_log_level);
+fn film_grain_encoder_opts(config: &Config) -> Vec<(&'static str, String)> {
+    let Some(film_grain) = &config.film_grain else {
+        return Vec::new();
+    };
+    match config.encoder.as_str() {
+        "libsvtav1" => {
+            let mut params = format!("film-grain={}:film-grain-denoise={}", film_grain.strength, film_grain.denoise as u8);
+            if let Some(table) = &film_grain.table {
+                params.push_str(&format!(":fgs-table={}", table.display()));
+            }
+            vec![("svtav1-params", params)]
+        },
+        "libaom-av1" => {
+            let mut opts = vec![
+                ("denoise-noise-level", film_grain.strength.to_string()),
+                ("enable-dnl-denoising", (film_grain.denoise as u8).to_string()),
+            ];
+            if let Some(table) = &film_grain.table {
+                opts.push(("aom-params", format!("film-grain-table={}", table.display())));
+            }
+            opts
+        },
+        _ => Vec::new(),
+    }
+}
 
-    let move_failed_files = config.move_failed_files;
-    let delete_almost_same_files = config.delete_almost_same_files;
+// scene cut and film grain can both go into the encoder's one "*-params" option, so those are joined
+fn encoder_opts(config: &Config) -> Vec<(&'static str, String)> {
+    let mut opts: Vec<(&'static str, String)> = Vec::new();
+    for (option, value) in scene_cut_encoder_opts(config).into_iter().chain(film_grain_encoder_opts(config)) {
+        match opts.iter_mut().find(|(existing, _)| option.ends_with("-params") && *existing == option) {
+            Some((_, existing_value)) => {
+                existing_value.push(':');
+                existing_value.push_str(&value);
+            },
+            None => opts.push((option, value)),
+        }
+    }
+    opts
+}
 
-    for video_path in video_paths {
-        log::trace!("Iterate path: {}", video_path.display());
+// External command deciding the output filename, it gets the source filename
+// as the last argument and prints the new filename to stdout
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct RenamerConfig {
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+    // error out instead of appending output_extension when the output lacks it
+    #[serde(default)]
+    strict_extension: bool,
+    // use the untransformed name instead of aborting when the renamer fails
+    #[serde(default)]
+    fallback_on_failure: bool,
+}
 
-        fs::create_dir_all(&encodnig_video_dir)?;
+// External command, it gets the source path as the last argument
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct HookConfig {
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+}
 
-        // file_stem sometimes treats the last part of the file name as extension
-        // so we impl the way below
-        let video_location_hash = hash_file_location(&video_path);
-        let encoding_video_path = encodnig_video_dir.join(&video_location_hash).with_extension("mkv");
-        let save_path = encoded_file_save_path(&video_path, &config)?;
+// Printed by target_policy_hook, e.g. {"mode":"vmaf","value":95} or {"mode":"crf","value":28}
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "mode", content = "value", rename_all = "lowercase")]
+enum TargetPolicy {
+    Vmaf(u8),
+    Crf(u8),
+}
 
-        let original_filename = video_path.file_name().ok_or(Error::InvalidVideoPath(video_path.clone()))?;
-        let save_dir = save_dir_for_filename(&original_filename, &default_save_dir, &save_dir_overrides);
-        fs::create_dir_all(&save_dir)?;
-        log::debug!("Save dir: ({}, {}, {:?}) -> {}", &original_filename.to_string_lossy().to_string(), default_save_dir.display(), save_dir_overrides, save_dir.display());
+// Number of files encoded at once, e.g. `concurrency = "auto"` or `concurrency = { fixed = 2 }`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Concurrency {
+    Fixed(usize),
+    Auto,
+}
 
-        let dst_video_filename = rename_for_linux_limit::new_filename(&video_path, Some(&save_dir))?;
-        let failed_copy_path = save_dir.join(dst_video_filename);
+// Phone videos are stored sideways with rotation metadata telling the player to turn them
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum RotationMode {
+    // keep the frames as stored and carry the rotation metadata over
+    Preserve,
+    // turn the frames upright, so the output needs no metadata
+    Bake,
+}
 
-        if is_junk(&video_path) {
-            println!("Removing junk file: {}", video_path.display());
-            fs::remove_file(&video_path)?;
-            continue;
-        }
+// What to do when an encode ends up bigger than its source, e.g. `on_larger_output = "discard_keep_original"`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum LargerOutputAction {
+    Keep,
+    // drop the output and leave the source where it is
+    DiscardKeepOriginal,
+    // drop the output and move the source into the save directory in its place
+    KeepOriginalMoveSource,
+}
 
-        if !guess_video_file(&video_path) {
-            println!("Skipping non-video file: {}", video_path.display());
-            continue;
-        }
+// What to do when the source doesn't compress under max_encoded_percent,
+// e.g. `on_max_encoded_percent_exceeded = { move_to = "/videos/incompressible" }` or `{ retry_with_percent = 90 }`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum MaxEncodedPercentAction {
+    // leave the source where it is
+    Keep,
+    MoveTo(PathBuf),
+    // search once more with a higher limit, kept like Keep if that doesn't fit either
+    RetryWithPercent(u8),
+}
 
-        if !is_valid_video_file(&video_path)? {
-            println!("Skipping invalid video file: {}", video_path.display());
-            continue;
+impl MaxEncodedPercentAction {
+    fn retry_percent(&self) -> Option<u8> {
+        match self {
+            Self::RetryWithPercent(percent) => Some(*percent),
+            _ => None,
         }
+    }
+}
 
-        if save_path.exists() {
-            if delete_almost_same_files {
-                if !is_valid_video_file(&save_path)? {
-                    return Err(anyhow!(Error::FoundInvalidVideoFileInSavedPath(save_path)));
-                }
-
-                let duration_of_saved_video = rough_video_secs(&save_path)?;
-                let duration_of_current_video = rough_video_secs(&video_path)?;
+// consumer GPUs limit concurrent NVENC sessions, so stay well below that
+const NVENC_AUTO_CONCURRENCY: usize = 2;
 
-                if jdt::almost_eq(duration_of_saved_video, duration_of_current_video, 0.01) {
-                    println!("Removing a file having duplicate name, almost equal duration video: {}", video_path.display());
-                    fs::remove_file(&video_path)?;
+impl Concurrency {
+    fn resolve(&self, encoder: &str) -> usize {
+        match self {
+            Self::Fixed(concurrency) => (*concurrency).max(1),
+            Self::Auto => {
+                let cores = thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1);
+                if is_nvenc_encoder(encoder) {
+                    NVENC_AUTO_CONCURRENCY.min(cores)
                 } else {
-                    println!("Skipping video for now, duplicated names, but different durations ({} != {}): {}", duration_of_saved_video, duration_of_current_video, save_path.display());
+                    cores
                 }
-            } else {
-                println!("Skipping video {} as it already exists in save directory", video_path.display());
-            }
-            continue;
+            },
         }
+    }
+}
 
-        if move_failed_files && failed_copy_path.exists() {
-            return Err(anyhow!(Error::ConflictFailedCopyPath(video_path, failed_copy_path)));
-        }
+// The log file is rotated to "<log_file>.1", "<log_file>.2", ... when it grows over max_bytes
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+struct LogRotationConfig {
+    max_bytes: u64,
+    max_files: usize,
+}
 
-        if encoding_video_path.exists() {
-            return Err(anyhow!(Error::ConflictVideoEncoding(video_path, encoding_video_path)));
+impl Default for LogRotationConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024,
+            max_files: 5,
         }
+    }
+}
 
-        println!("Encoding video: {}", video_path.display());
-        let success = match exec_ab_av1(&video_path, &encoding_video_path, opts.target_vmaf, false, &inherited_log_level, &config) {
-            Ok(_) => true,
-            Err(e) => {
-                match e.downcast_ref::<Error>() {
-                    Some(Error::AbAv1CommandFailed(_)) => false,
-                    _ => return Err(e),
-                }
-            }
-        };
+// Encodes into a "<name>.hls" directory with a VOD playlist and fMP4 segments, force crf only
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+struct HlsConfig {
+    segment_secs: u32,
+}
 
-        if success {
-            if encoding_video_path.exists() && !is_valid_video_file(&encoding_video_path)? {
-                log::warn!("Encoding failed for {:?}: Invalid video file", video_path);
-                fs::remove_file(&encoding_video_path)?;
-                continue;
-            }
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            segment_secs: 6,
+        }
+    }
+}
 
-            let start_saving = std::time::Instant::now();
-            println!("Saving video to: {}", save_path.display());
-            jdt::rename_file(&encoding_video_path, &save_path)?;
-            let elapsed = start_saving.elapsed();
-            if elapsed.as_secs() > 10 {
-                println!("Saved in {:.2} sec", elapsed.as_secs_f64());
-            }
+// e.g. program = "largest" or program = { id = 1025 }
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ProgramSelection {
+    First,
+    // the one with the highest video resolution, the main channel of a broadcast usually
+    Largest,
+    Id(u32),
+}
 
-            if !config.keep_original {
-                println!("Removing original video ...");
-                fs::remove_file(&video_path)?;
-                log::debug!("Removed original video {:?}", video_path);
-            }
-        } else {
-            if encoding_video_path.exists() {
-                fs::remove_file(&encoding_video_path)?;
-            }
-            if move_failed_files {
-                println!("Moving failed video ...");
-                jdt::rename_file(&video_path, &failed_copy_path)?;
-            }
+// Grain defeats AV1's efficiency, applied to sources flagged with `denoise = true` in their sidecar,
+// and with grain_psnr_below, to any source whose sample loses that much PSNR to the filter itself
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+struct DenoiseConfig {
+    filter: DenoiseFilter,
+    // hqdn3d luma_spatial or nlmeans s, the filter's own default when unset
+    strength: Option<f64>,
+    grain_psnr_below: Option<f64>,
+}
+
+impl Default for DenoiseConfig {
+    fn default() -> Self {
+        Self {
+            filter: DenoiseFilter::Hqdn3d,
+            strength: None,
+            grain_psnr_below: None,
         }
     }
-
-    Ok(())
 }
 
-fn run_debug_single_command(opts: DebugSingleOpts, config: Config) -> Result<()> {
-    let output_path = config.save_dir.join("output.mp4");
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum DenoiseFilter {
+    // fast, smooths fine detail along with the grain
+    Hqdn3d,
+    // keeps detail better, but is many times slower
+    Nlmeans,
+}
 
-    log::debug!("Running debug single command with opts: {:?}", opts);
-    log::debug!("Output path: {:?}", output_path);
+impl DenoiseConfig {
+    fn filter(&self) -> String {
+        match (self.filter, self.strength) {
+            (DenoiseFilter::Hqdn3d, Some(strength)) => format!("hqdn3d=luma_spatial={}", strength),
+            (DenoiseFilter::Hqdn3d, None) => "hqdn3d".to_string(),
+            (DenoiseFilter::Nlmeans, Some(strength)) => format!("nlmeans=s={}", strength),
+            (DenoiseFilter::Nlmeans, None) => "nlmeans".to_string(),
+        }
+    }
+}
 
-    exec_ab_av1(&opts.video_path, &output_path, opts.target_vmaf, true, "debug", &config)
+// Grain is measured and removed before encoding, and described in the bitstream for the decoder to synthesize,
+// e.g. `film_grain = { strength = 8 }`, a table from a photon noise or grain estimation tool replaces the estimate
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+struct FilmGrainConfig {
+    // 0 to 50, higher synthesizes more grain
+    strength: u8,
+    // off keeps the encoder from denoising, for sources already denoised by the denoise filter
+    denoise: bool,
+    table: Option<PathBuf>,
 }
 
-fn run_force_crf_single_command(opts: ForceCrfSingleOpts, config: Config) -> Result<()> {
-    let save_dir = &config.save_dir;
-    let encodnig_video_dir = config.tmp_dir.join("encoding");
-    let video_path = &opts.video_path;
-    fs::create_dir_all(&save_dir)?;
-    fs::create_dir_all(&encodnig_video_dir)?;
+impl Default for FilmGrainConfig {
+    fn default() -> Self {
+        Self {
+            strength: 8,
+            denoise: true,
+            table: None,
+        }
+    }
+}
 
-    let video_location_hash = hash_file_location(&video_path);
-    let encoding_video_path = encodnig_video_dir.join(&video_location_hash).with_extension("mkv");
-    let save_path = encoded_file_save_path(&opts.video_path, &config)?;
+// Animated preview saved next to each output as "<name>.preview.<format>", e.g. `preview = { width = 480 }`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+struct PreviewConfig {
+    duration_secs: f64,
+    fps: u32,
+    width: u32,
+    format: PreviewFormat,
+}
 
-    if save_path.exists() {
-        return Err(anyhow!(Error::SingleEncodeSavePathAlreadyExists(save_path)));
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self {
+            duration_secs: 3.0,
+            fps: 10,
+            width: 320,
+            format: PreviewFormat::Webp,
+        }
     }
+}
 
-    if encoding_video_path.exists() {
-        return Err(anyhow!(Error::ConflictVideoEncoding(video_path.clone(), encoding_video_path.clone())));
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PreviewFormat {
+    Gif,
+    Webp,
+}
+
+impl PreviewFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+        }
     }
+}
 
-    println!("Encoding video: {}", video_path.display());
-    exec_force_crf_ffmpeg(&opts.video_path, &encoding_video_path, opts.crf)?;
+// Measured against the source before saving and recorded on the saved output as "user.batchav1.<metric>",
+// e.g. `quality_metrics_to_record = ["vmaf", "psnr", "ssim"]`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum QualityMetric {
+    Vmaf,
+    Psnr,
+    Ssim,
+}
 
-    if encoding_video_path.exists() && !is_valid_video_file(&encoding_video_path)? {
-        fs::remove_file(&encoding_video_path)?;
-        return Err(anyhow!(Error::SingleEncodeFailedWithInvalidEncodedFile(video_path.clone(), encoding_video_path.clone())));
+impl QualityMetric {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Vmaf => "vmaf",
+            Self::Psnr => "psnr",
+            Self::Ssim => "ssim",
+        }
     }
 
-    let start_saving = std::time::Instant::now();
-    println!("Saving video to: {}", save_path.display());
-    jdt::rename_file(&encoding_video_path, &save_path)?;
-    let elapsed = start_saving.elapsed();
-    if elapsed.as_secs() > 10 {
-        println!("Saved in {:.2} sec", elapsed.as_secs_f64());
+    fn filter(&self) -> &'static str {
+        match self {
+            Self::Vmaf => "libvmaf",
+            Self::Psnr => "psnr",
+            Self::Ssim => "ssim",
+        }
     }
 
-    if !config.keep_original {
-        println!("Removing original video ...");
-        fs::remove_file(&video_path)?;
-        log::debug!("Removed original video {:?}", video_path);
+    // libvmaf logs "VMAF score: 95.123456", psnr "PSNR y:... average:42.10 min:...", ssim "SSIM Y:... All:0.987654 (19.08)"
+    fn parse_score(&self, line: &str) -> Option<f64> {
+        let score_str = match self {
+            Self::Vmaf => line.split_once("VMAF score:")?.1.trim(),
+            Self::Psnr => line.split_once("PSNR ")?.1.split_once("average:")?.1.split_whitespace().next()?,
+            Self::Ssim => line.split_once("SSIM ")?.1.split_once("All:")?.1.split_whitespace().next()?,
+        };
+        score_str.parse().ok()
     }
+}
 
-    Ok(())
+const HLS_OUTPUT_EXTENSION: &str = "hls";
+const HLS_PLAYLIST_FILENAME: &str = "index.m3u8";
+const HLS_INIT_FILENAME: &str = "init.mp4";
+
+struct RotatingLogFile {
+    path: PathBuf,
+    rotation: LogRotationConfig,
+    file: fs::File,
+    size: u64,
 }
 
-fn exec_ab_av1(input_path: impl AsRef<Path>, output_path: impl AsRef<Path>, target_vmaf: u8, debug_intermediate_files: bool, log_level: impl AsRef<str>, config: &Config) -> Result<()> {
-    let input_path = input_path.as_ref();
-    let output_path = output_path.as_ref();
-    let log_level = log_level.as_ref();
-    let tmp_dir = if debug_intermediate_files {
-        PathBuf::from(".")
-    } else {
-        config.tmp_dir.join("ab_av1_tmp")
-    };
-    fs::create_dir_all(&tmp_dir)?;
-    let mut command = Command::new("ab-av1");
-    command
-        .env("RUST_BACKTRACE", "1")
-        .env("RUST_LOG", format!("ab_av1={}", log_level))
-        .arg("auto-encode")
-        .arg("-e").arg("av1_nvenc")
-        .arg("--cuda")
-        .arg("--enc").arg("v:b=0").arg("--enc").arg("rc=vbr")
-        .arg("--enc").arg("temporal-aq=1")
-        .arg("--enc").arg("tune=hq")
-        .arg("--enc").arg("rc-lookahead=32")
-        .arg("--enc").arg("fps_mode=passthrough")
-        .arg("--enc").arg("sn").arg("--enc").arg("dn").arg("--acodec").arg("aac")
-        .arg("--preset").arg("p7")
-        .arg("--min-vmaf").arg(target_vmaf.to_string())
-        .arg("--min-crf").arg(config.min_crf.to_string())
-        .arg("--max-crf").arg(config.max_crf.to_string())
-        .arg("--max-encoded-percent").arg(config.max_encoded_percent.to_string())
-        .arg("--temp-dir").arg(tmp_dir)
-        .arg("-i").arg(input_path)
-        .arg("-o").arg(output_path);
+impl RotatingLogFile {
+    fn open(path: impl AsRef<Path>, rotation: LogRotationConfig) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, rotation, file, size })
+    }
 
-    if debug_intermediate_files {
-        command.arg("--keep");
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut rotated_path = self.path.as_os_str().to_os_string();
+        rotated_path.push(format!(".{}", index));
+        PathBuf::from(rotated_path)
     }
-    log::debug!("Command: {:?}", command);
-    let status = command.status()?;
-    log::debug!("Command status: {:?}", status);
-    if status.success() {
-       Ok(())
-    } else {
-        Err(anyhow!(Error::AbAv1CommandFailed(status)))
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.rotation.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.rotation.max_files).rev() {
+                let rotated_path = self.rotated_path(index);
+                if rotated_path.exists() {
+                    fs::rename(&rotated_path, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
     }
 }
 
-// VMAF sometimes gives wrong results than human-sense score, for example, the reference video with VHD frame-vibrations, etc.
-// So, we support the feature just to set constant quality for ffmpeg
-fn exec_force_crf_ffmpeg(input_path: impl AsRef<Path>, output_path: impl AsRef<Path>, crf: u8) -> Result<()> {
-    let input_path = input_path.as_ref();
-    let output_path = output_path.as_ref();
-    let mut command = Command::new("ffmpeg");
-    command
-        .arg("-y")
-        .arg("-hwaccel").arg("cuda").arg("-hwaccel_output_format").arg("cuda")
-        .arg("-i").arg(input_path)
-        .arg("-c:v").arg("av1_nvenc")
-        .arg("-v:b").arg("0").arg("-rc").arg("vbr")
-        .arg("-preset").arg("p7")
-        .arg("-fps_mode").arg("passthrough")
-        .arg("-tune").arg("hq")
-        .arg("-temporal-aq").arg("1")
-        .arg("-rc-lookahead").arg("32")
-        .arg("-g").arg("300")
-        .arg("-cq").arg(crf.to_string())
-        .arg("-highbitdepth").arg("1")
-        .arg("-sn").arg("-dn")
-        .arg("-acodec").arg("aac")
-        .arg(output_path);
+impl Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.rotation.max_bytes {
+            self.rotate()?;
+        }
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        Ok(len)
+    }
 
-    log::debug!("Command: {:?}", command);
-    let status = command.status()?;
-    log::debug!("Command status: {:?}", status);
-    if status.success() {
-       Ok(())
-    } else {
-        Err(anyhow!(Error::ForceCrfFfmpegCommandFailed(status)))
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
     }
 }
 
-fn encoded_file_save_path(video_path: impl AsRef<Path>, config: &Config) -> Result<PathBuf> {
-    let video_path = video_path.as_ref();
-    let save_dir = &config.save_dir;
-    let save_dir_overrides = &config.save_dir_overrides;
-
-    // file_stem sometimes treats the last part of the file name as extension
-    // so we impl the way below
-    let video_filename = video_path.file_name().ok_or(Error::InvalidVideoPath(video_path.to_path_buf()))?;
-    let save_dir = save_dir_for_filename(&video_path, save_dir, save_dir_overrides);
-    let mut iter = video_filename.as_encoded_bytes().rsplitn(1, |&b| b == b'.');
-    let video_slug = iter.next().ok_or(Error::InvalidVideoPath(video_path.to_path_buf()))?;
-    let video_slug = String::from_utf8_lossy(video_slug).to_string();
+// env_logger writes to a single target, so tee into the log file and stderr here
+struct LogWriter {
+    file: RotatingLogFile,
+    to_stderr: bool,
+}
 
-    let pre_save_path = save_dir.join(&video_slug).with_extension("mkv");
-    let save_video_filename = rename_for_linux_limit::new_filename(&pre_save_path, Some(&save_dir))?;
-    let save_path = save_dir.join(save_video_filename);
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.to_stderr {
+            std::io::stderr().write_all(buf)?;
+        }
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
 
-    Ok(save_path)
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
 }
 
-fn guess_video_file(path: impl AsRef<Path>) -> bool {
-    let path = path.as_ref();
-    let guess = mime_guess::from_path(path);
-    let mut iter = guess.iter();
-    iter.any(|mime| mime.type_() == "video")
+fn init_logger(config: &Config) -> Result<()> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(log_file) = &config.log_file {
+        let file = RotatingLogFile::open(log_file, config.log_rotation.clone())?;
+        builder.target(env_logger::Target::Pipe(Box::new(LogWriter { file, to_stderr: config.log_to_stderr })));
+    }
+    builder.try_init()?;
+    Ok(())
 }
 
-fn is_valid_video_file(video_path: impl AsRef<Path>) -> Result<bool> {
-    let video_path = video_path.as_ref();
+const APPEND_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// A thread of its own owns the file and appends whole lines in the order they are sent,
+// so lines from concurrent workers never interleave. Buffered, but flushed every
+// APPEND_FLUSH_INTERVAL so a crash loses little, and on drop, which waits for the thread
+struct AppendWriter {
+    sender: Option<mpsc::Sender<String>>,
+    writer_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl AppendWriter {
+    fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let (sender, receiver) = mpsc::channel::<String>();
+        let writer_thread = thread::spawn(move || {
+            let mut file = std::io::BufWriter::new(file);
+            let mut last_flush = Instant::now();
+            loop {
+                match receiver.recv_timeout(APPEND_FLUSH_INTERVAL) {
+                    Ok(line) => {
+                        if let Err(e) = writeln!(file, "{}", line) {
+                            log::warn!("Failed to append to {:?}: {:?}", path, e);
+                        }
+                        if last_flush.elapsed() < APPEND_FLUSH_INTERVAL {
+                            continue;
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if let Err(e) = file.flush() {
+                    log::warn!("Failed to flush {:?}: {:?}", path, e);
+                }
+                last_flush = Instant::now();
+            }
+            if let Err(e) = file.flush() {
+                log::warn!("Failed to flush {:?}: {:?}", path, e);
+            }
+        });
+        Ok(Self { sender: Some(sender), writer_thread: Some(writer_thread) })
+    }
+
+    fn append_line(&self, line: impl Into<String>) {
+        let sender = self.sender.as_ref().expect("sender must live until drop");
+        if sender.send(line.into()).is_err() {
+            log::warn!("Append writer thread is gone, dropping a line");
+        }
+    }
+}
+
+impl Drop for AppendWriter {
+    fn drop(&mut self) {
+        // closing the channel lets the thread write what's queued and exit
+        drop(self.sender.take());
+        if let Some(writer_thread) = self.writer_thread.take() {
+            if writer_thread.join().is_err() {
+                log::warn!("Append writer thread panicked");
+            }
+        }
+    }
+}
+
+// Per-file overrides read from "<source>.batchav1.toml", only these fields are overridable
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Sidecar {
+    skip: bool,
+    target_vmaf: Option<u8>,
+    // encode with this constant quality instead of searching for target_vmaf
+    crf: Option<u8>,
+    min_crf: Option<u8>,
+    max_crf: Option<u8>,
+    max_encoded_percent: Option<u8>,
+    keep_original: Option<bool>,
+    encoder: Option<String>,
+    max_height: Option<u32>,
+    audio_tracks: Option<Vec<AudioTrackConfig>>,
+    // forces denoise on or off, instead of the grain probe
+    denoise: Option<bool>,
+}
+
+impl Sidecar {
+    fn load(video_path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let video_path = video_path.as_ref();
+        let mut sidecar_path = video_path.as_os_str().to_os_string();
+        sidecar_path.push(".batchav1.toml");
+        let sidecar_path = PathBuf::from(sidecar_path);
+        if !sidecar_path.exists() {
+            return Ok(None);
+        }
+        let sidecar_str = fs::read_to_string(&sidecar_path)?;
+        let sidecar = toml::from_str(&sidecar_str).map_err(|e| Error::InvalidSidecar(sidecar_path.clone(), e.to_string()))?;
+        log::debug!("Sidecar {:?}: {:?}", sidecar_path, sidecar);
+        Ok(Some(sidecar))
+    }
+
+    fn apply(&self, config: &Config) -> Result<Config> {
+        let mut config = config.clone();
+        if let Some(min_crf) = self.min_crf {
+            config.min_crf = min_crf;
+        }
+        if let Some(max_crf) = self.max_crf {
+            config.max_crf = max_crf;
+        }
+        if let Some(max_encoded_percent) = self.max_encoded_percent {
+            config.max_encoded_percent = max_encoded_percent;
+        }
+        if let Some(keep_original) = self.keep_original {
+            config.keep_original = keep_original;
+        }
+        if let Some(encoder) = &self.encoder {
+            config.encoder = encoder.clone();
+        }
+        if let Some(max_height) = self.max_height {
+            config.max_height = Some(max_height);
+        }
+        if let Some(audio_tracks) = &self.audio_tracks {
+            config.audio_tracks = audio_tracks.clone();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+// One output audio track, e.g. a stereo downmix of the first source track
+// { codec = "aac", bitrate = "192k", channels = 2, source_track = 0 }
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct AudioTrackConfig {
+    codec: String,
+    bitrate: Option<String>,
+    channels: Option<u32>,
+    #[serde(default)]
+    source_track: usize,
+}
+
+// ffmpeg's own opus and vorbis encoders refuse to run without -strict experimental
+fn is_experimental_audio_encoder(codec: &str) -> bool {
+    matches!(codec, "opus" | "vorbis")
+}
+
+fn is_audio_codec_supported_by_container(codec: &str, extension: &str) -> bool {
+    match extension.to_lowercase().as_str() {
+        "mkv" => true,
+        "mp4" => matches!(codec, "aac" | "libfdk_aac" | "ac3" | "eac3" | "libopus" | "opus" | "libmp3lame" | "mp3" | "alac" | "flac" | "copy"),
+        "webm" => matches!(codec, "libopus" | "opus" | "libvorbis" | "vorbis"),
+        _ => false,
+    }
+}
+
+// A rendition is one output of the ladder encoded from a single source,
+// e.g. { name = "1080p", target_vmaf = 95, height = 1080 }
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct RenditionConfig {
+    name: String,
+    target_vmaf: Option<u8>,
+    height: Option<u32>,
+}
+
+#[derive(Debug)]
+struct EncodeTarget {
+    name: Option<String>,
+    save_path: PathBuf,
+    encoding_video_path: PathBuf,
+    target_vmaf: u8,
+    crf: Option<u8>,
+    scale_height: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileOutcome {
+    Encoded,
+    // encoded, but only at min_acceptable_vmaf instead of the target
+    Salvaged,
+    Failed,
+    Skipped,
+    Removed,
+    // kept, as no crf got it under max_encoded_percent
+    Incompressible,
+    // handed to a save worker, which records the final outcome
+    Queued,
+}
+
+#[derive(Debug, Default)]
+struct RunSummary {
+    encoded: Vec<PathBuf>,
+    salvaged: Vec<PathBuf>,
+    failed: Vec<PathBuf>,
+    incompressible: Vec<PathBuf>,
+    skipped: usize,
+    removed: usize,
+    skipped_fatal: Vec<(PathBuf, String)>,
+}
+
+impl FileOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Encoded => "encoded",
+            Self::Salvaged => "salvaged",
+            Self::Failed => "failed",
+            Self::Skipped => "skipped",
+            Self::Removed => "removed",
+            Self::Incompressible => "incompressible",
+            Self::Queued => "queued",
+        }
+    }
+}
+
+// Every run appends to it: a files row per processed source, an outputs row per saved output
+#[derive(Debug)]
+struct HistoryDb {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl HistoryDb {
+    fn open(db_path: impl AsRef<Path>) -> Result<Self> {
+        let db_path = db_path.as_ref();
+        let connection = rusqlite::Connection::open(db_path)?;
+        connection.execute_batch("
+            CREATE TABLE IF NOT EXISTS files (
+                id INTEGER PRIMARY KEY,
+                source_path TEXT NOT NULL,
+                location_hash TEXT NOT NULL,
+                outcome TEXT NOT NULL,
+                recorded_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS outputs (
+                id INTEGER PRIMARY KEY,
+                source_path TEXT NOT NULL,
+                location_hash TEXT NOT NULL,
+                output_path TEXT NOT NULL,
+                source_size INTEGER NOT NULL,
+                output_size INTEGER NOT NULL,
+                encoder TEXT NOT NULL,
+                vmaf REAL,
+                crf REAL,
+                duration_secs REAL,
+                recorded_at INTEGER NOT NULL,
+                reference_size INTEGER,
+                source_mtime INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS outputs_location_hash ON outputs (location_hash);
+        ")?;
+        // databases from before reference_sizes and reuse_recorded_crf lack their columns
+        for column in ["reference_size", "source_mtime"] {
+            let has_column: bool = connection.query_row("SELECT COUNT(*) > 0 FROM pragma_table_info('outputs') WHERE name = ?1", [column], |row| row.get(0))?;
+            if !has_column {
+                connection.execute_batch(&format!("ALTER TABLE outputs ADD COLUMN {} INTEGER", column))?;
+            }
+        }
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    fn record_file(&self, video_path: impl AsRef<Path>, outcome: FileOutcome) -> Result<()> {
+        let video_path = video_path.as_ref();
+        let connection = self.connection.lock().expect("history lock must not be poisoned");
+        connection.execute(
+            "INSERT INTO files (source_path, location_hash, outcome, recorded_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![video_path.to_string_lossy(), hash_file_location(video_path), outcome.as_str(), unix_timestamp()],
+        )?;
+        Ok(())
+    }
+
+    // the latest output recorded for the source that is still there, renamer or save_dir changes don't matter
+    fn find_output(&self, video_path: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+        let video_path = video_path.as_ref();
+        let connection = self.connection.lock().expect("history lock must not be poisoned");
+        let mut statement = connection.prepare("SELECT output_path FROM outputs WHERE location_hash = ?1 ORDER BY id DESC")?;
+        let output_paths = statement.query_map([hash_file_location(video_path)], |row| row.get::<_, String>(0))?;
+        for output_path in output_paths {
+            let output_path = PathBuf::from(output_path?);
+            if output_path.exists() {
+                return Ok(Some(output_path));
+            }
+        }
+        Ok(None)
+    }
+
+    fn record_output(&self, video_path: impl AsRef<Path>, output_path: impl AsRef<Path>, source_size: u64, stats: AbAv1Stats, duration_secs: Option<f64>, config: &Config) -> Result<()> {
+        let video_path = video_path.as_ref();
+        let output_path = output_path.as_ref();
+        let output_size = encoded_output_size(output_path)?;
+        let source_mtime = fs::metadata(video_path)?.mtime();
+        let connection = self.connection.lock().expect("history lock must not be poisoned");
+        connection.execute(
+            "INSERT INTO outputs (source_path, location_hash, output_path, source_size, output_size, encoder, vmaf, crf, duration_secs, recorded_at, reference_size, source_mtime) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            rusqlite::params![video_path.to_string_lossy(), hash_file_location(video_path), output_path.to_string_lossy(), source_size, output_size, config.encoder, stats.vmaf, stats.crf, duration_secs, unix_timestamp(), reference_size(video_path), source_mtime],
+        )?;
+        Ok(())
+    }
+
+    // (crf, VMAF) of the latest search for the source with this encoder, none once the source changed size or mtime
+    fn find_crf(&self, video_path: impl AsRef<Path>, encoder: &str) -> Result<Option<(f32, f64)>> {
+        let video_path = video_path.as_ref();
+        let metadata = fs::metadata(video_path)?;
+        let connection = self.connection.lock().expect("history lock must not be poisoned");
+        let recorded = connection.query_row(
+            "SELECT crf, vmaf FROM outputs WHERE location_hash = ?1 AND encoder = ?2 AND source_size = ?3 AND source_mtime = ?4 AND crf IS NOT NULL AND vmaf IS NOT NULL ORDER BY id DESC LIMIT 1",
+            rusqlite::params![hash_file_location(video_path), encoder, metadata.len(), metadata.mtime()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        );
+        match recorded {
+            Ok(recorded) => Ok(Some(recorded)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn load_reference_sizes(reference_sizes_path: impl AsRef<Path>) -> Result<HashMap<PathBuf, u64>> {
+    let reference_sizes_path = reference_sizes_path.as_ref();
+    let reference_sizes_str = fs::read_to_string(reference_sizes_path)?;
+    let reference_sizes: HashMap<PathBuf, u64> = serde_json::from_str(&reference_sizes_str).map_err(|e| Error::InvalidReferenceSizes(reference_sizes_path.to_path_buf(), e.to_string()))?;
+    // canonical keys, so the walk's relative or symlinked paths still find them
+    Ok(reference_sizes.into_iter().map(|(path, size)| (fs::canonicalize(&path).unwrap_or(path), size)).collect())
+}
+
+fn reference_size(video_path: impl AsRef<Path>) -> Option<u64> {
+    let video_path = video_path.as_ref();
+    let reference_sizes = REFERENCE_SIZES.get()?;
+    reference_sizes.get(video_path).or_else(|| fs::canonicalize(video_path).ok().and_then(|video_path| reference_sizes.get(&video_path))).copied()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ReviewReason {
+    VmafUnreachable,
+    AspectRatio,
+    DecodeErrors,
+    // added by hand with the review command
+    Manual,
+}
+
+impl ReviewReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReviewReason::VmafUnreachable => "vmaf_unreachable",
+            ReviewReason::AspectRatio => "aspect_ratio",
+            ReviewReason::DecodeErrors => "decode_errors",
+            ReviewReason::Manual => "manual",
+        }
+    }
+}
+
+// One "<location hash>\t<reason>\t<source path>" line per source, the hash of the canonical path is the key
+// and the rest is for whoever reads the file
+#[derive(Debug)]
+struct ReviewList {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, (String, PathBuf)>>,
+}
+
+impl ReviewList {
+    fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut entries = HashMap::new();
+        match fs::read_to_string(path) {
+            Ok(review_list_str) => {
+                for line in review_list_str.lines().filter(|line| !line.trim().is_empty()) {
+                    let mut fields = line.splitn(3, '\t');
+                    if let (Some(key), Some(reason), Some(video_path)) = (fields.next(), fields.next(), fields.next()) {
+                        entries.insert(key.to_string(), (reason.to_string(), PathBuf::from(video_path)));
+                    } else {
+                        log::warn!("Ignoring malformed line in review list {:?}: {:?}", path, line);
+                    }
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Self { path: path.to_path_buf(), entries: Mutex::new(entries) })
+    }
+
+    fn key(video_path: impl AsRef<Path>) -> String {
+        let video_path = video_path.as_ref();
+        hash_file_location(fs::canonicalize(video_path).unwrap_or(video_path.to_path_buf()))
+    }
+
+    fn contains(&self, video_path: impl AsRef<Path>) -> bool {
+        self.entries.lock().expect("review list lock must not be poisoned").contains_key(&Self::key(video_path))
+    }
+
+    fn add(&self, video_path: impl AsRef<Path>, reason: ReviewReason) -> Result<bool> {
+        let video_path = video_path.as_ref();
+        let mut entries = self.entries.lock().expect("review list lock must not be poisoned");
+        let source_path = fs::canonicalize(video_path).unwrap_or(video_path.to_path_buf());
+        if entries.insert(Self::key(video_path), (reason.as_str().to_string(), source_path)).is_some() {
+            return Ok(false);
+        }
+        self.save(&entries)?;
+        Ok(true)
+    }
+
+    // by the source path, or by the hash as listed, for sources that are gone
+    fn remove(&self, video_path: impl AsRef<Path>) -> Result<bool> {
+        let video_path = video_path.as_ref();
+        let mut entries = self.entries.lock().expect("review list lock must not be poisoned");
+        if entries.remove(&Self::key(video_path)).is_none() && entries.remove(video_path.to_string_lossy().as_ref()).is_none() {
+            return Ok(false);
+        }
+        self.save(&entries)?;
+        Ok(true)
+    }
+
+    // written whole to a temp file and renamed, so an interrupted write never loses the list
+    fn save(&self, entries: &HashMap<String, (String, PathBuf)>) -> Result<()> {
+        let mut lines: Vec<String> = entries.iter().map(|(key, (reason, video_path))| format!("{}\t{}\t{}\n", key, reason, video_path.display())).collect();
+        lines.sort_by(|a, b| a.split('\t').nth(2).cmp(&b.split('\t').nth(2)));
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, lines.concat())?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+// the review list is a side record like the history, a failed write only means the source comes up again
+fn flag_for_review(video_path: impl AsRef<Path>, reason: ReviewReason, config: &Config) {
+    let video_path = video_path.as_ref();
+    let Some(review_list) = REVIEW_LIST.get().filter(|_| config.review_on.contains(&reason)) else {
+        return;
+    };
+    match review_list.add(video_path, reason) {
+        Ok(true) => report!("Added to review list ({}): {}", reason.as_str(), video_path.display()),
+        Ok(false) => {},
+        Err(e) => log::warn!("Failed to add {:?} to review list: {:?}", video_path, e),
+    }
+}
+
+// the history is a side record, so a failed write never stops the batch
+fn record_history_file(video_path: impl AsRef<Path>, outcome: FileOutcome) {
+    let video_path = video_path.as_ref();
+    let Some(history_db) = HISTORY_DB.get() else {
+        return;
+    };
+    if outcome == FileOutcome::Queued {
+        return;
+    }
+    if let Err(e) = history_db.record_file(video_path, outcome) {
+        log::warn!("Failed to record history of {:?}: {:?}", video_path, e);
+    }
+}
+
+impl RunSummary {
+    fn record(&mut self, video_path: impl AsRef<Path>, outcome: FileOutcome) {
+        let video_path = video_path.as_ref();
+        match outcome {
+            FileOutcome::Encoded => self.encoded.push(video_path.to_path_buf()),
+            FileOutcome::Salvaged => self.salvaged.push(video_path.to_path_buf()),
+            FileOutcome::Failed => self.failed.push(video_path.to_path_buf()),
+            FileOutcome::Skipped => self.skipped += 1,
+            FileOutcome::Removed => self.removed += 1,
+            FileOutcome::Incompressible => self.incompressible.push(video_path.to_path_buf()),
+            FileOutcome::Queued => {},
+        }
+    }
+
+    fn has_failures(&self) -> bool {
+        !self.failed.is_empty() || !self.skipped_fatal.is_empty()
+    }
+
+    fn print(&self) {
+        println!("Summary: {} encoded, {} salvaged, {} failed, {} incompressible, {} skipped, {} removed, {} skipped on fatal error", self.encoded.len(), self.salvaged.len(), self.failed.len(), self.incompressible.len(), self.skipped, self.removed, self.skipped_fatal.len());
+        for video_path in &self.salvaged {
+            println!("  Salvaged at minimum acceptable VMAF: {}", video_path.display());
+        }
+        for video_path in &self.failed {
+            println!("  Failed: {}", video_path.display());
+        }
+        for video_path in &self.incompressible {
+            println!("  Incompressible under max_encoded_percent: {}", video_path.display());
+        }
+        for (video_path, reason) in &self.skipped_fatal {
+            println!("  Skipped on fatal error: {} ({})", video_path.display(), reason);
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct RunStatus {
+    pid: u32,
+    started_at: u64,
+    updated_at: u64,
+    index: usize,
+    current_file: Option<PathBuf>,
+    finished: bool,
+}
+
+// Small JSON status for external monitoring, rewritten on each file transition
+// and, if an interval is configured, periodically so stale timestamps mean a stuck process.
+// Every write happens under the status lock, so concurrent workers and the timer never interleave
+struct StatusFile {
+    path: PathBuf,
+    status: Arc<Mutex<RunStatus>>,
+}
+
+impl StatusFile {
+    fn start(path: impl AsRef<Path>, interval_secs: Option<u64>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let now = unix_timestamp();
+        let status = Arc::new(Mutex::new(RunStatus {
+            pid: std::process::id(),
+            started_at: now,
+            updated_at: now,
+            index: 0,
+            current_file: None,
+            finished: false,
+        }));
+        write_status_file(&path, &status.lock().expect("status lock must not be poisoned"))?;
+
+        if let Some(interval_secs) = interval_secs {
+            let path = path.clone();
+            let status = Arc::clone(&status);
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(interval_secs));
+                let mut status = status.lock().expect("status lock must not be poisoned");
+                if status.finished {
+                    break;
+                }
+                status.updated_at = unix_timestamp();
+                if let Err(e) = write_status_file(&path, &status) {
+                    log::warn!("Failed to write status file {:?}: {:?}", path, e);
+                }
+            });
+        }
+
+        Ok(Self { path, status })
+    }
+
+    fn update(&self, index: usize, current_file: Option<&Path>) -> Result<()> {
+        let mut status = self.status.lock().expect("status lock must not be poisoned");
+        status.updated_at = unix_timestamp();
+        status.index = index;
+        status.current_file = current_file.map(|path| path.to_path_buf());
+        write_status_file(&self.path, &status)
+    }
+
+    fn finish(&self) -> Result<()> {
+        let mut status = self.status.lock().expect("status lock must not be poisoned");
+        status.updated_at = unix_timestamp();
+        status.current_file = None;
+        status.finished = true;
+        write_status_file(&self.path, &status)
+    }
+}
+
+// Counting semaphore, network mounts time out when too many ffprobe processes hit them at once,
+// and NVENC fails encodes beyond the sessions the card allows
+#[derive(Debug)]
+struct ProcessLimit {
+    max_running: usize,
+    running: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ProcessLimit {
+    fn new(max_running: usize) -> Self {
+        Self { max_running: max_running.max(1), running: Mutex::new(0), released: Condvar::new() }
+    }
+
+    fn acquire(&self) -> ProcessPermit<'_> {
+        let running = self.running.lock().expect("process limit lock must not be poisoned");
+        let mut running = self.released.wait_while(running, |running| *running >= self.max_running).expect("process limit lock must not be poisoned");
+        *running += 1;
+        ProcessPermit { limit: self }
+    }
+}
+
+struct ProcessPermit<'a> {
+    limit: &'a ProcessLimit,
+}
+
+impl Drop for ProcessPermit<'_> {
+    fn drop(&mut self) {
+        *self.limit.running.lock().expect("process limit lock must not be poisoned") -= 1;
+        self.limit.released.notify_one();
+    }
+}
+
+fn acquire_probe_permit() -> Option<ProcessPermit<'static>> {
+    PROBE_LIMIT.get().map(|limit| limit.acquire())
+}
+
+// held for the whole encode, other encoders don't take a session
+fn acquire_gpu_session_permit(config: &Config) -> Option<ProcessPermit<'static>> {
+    if !is_nvenc_encoder(&config.encoder) {
+        return None;
+    }
+    // initialized here, so commands that never encode don't run nvidia-smi
+    let limits = GPU_SESSION_LIMITS.get_or_init(|| gpu_session_limits(config));
+    let cuda_device = CUDA_DEVICE.with(|current| *current.borrow());
+    limits.get(&cuda_device).map(|limit| limit.acquire())
+}
+
+// each card has its own sessions, without cuda_devices there's only the default one
+fn gpu_session_limits(config: &Config) -> HashMap<Option<u32>, ProcessLimit> {
+    let Some(max_gpu_sessions) = config.max_gpu_sessions.or_else(detect_gpu_session_limit) else {
+        return HashMap::new();
+    };
+    log::debug!("Max GPU sessions per device: {}", max_gpu_sessions);
+    let cuda_devices: Vec<Option<u32>> = if config.cuda_devices.is_empty() {
+        vec![None]
+    } else {
+        config.cuda_devices.iter().copied().map(Some).collect()
+    };
+    cuda_devices.into_iter().map(|cuda_device| (cuda_device, ProcessLimit::new(max_gpu_sessions))).collect()
+}
+
+// GeForce drivers allowed 3 sessions, 5 from 530 and 8 from 550, professional cards have no limit
+fn detect_gpu_session_limit() -> Option<usize> {
+    let mut command = Command::new("nvidia-smi");
+    command
+        .arg("--query-gpu=name,driver_version")
+        .arg("--format=csv,noheader");
+    log::debug!("Command: {}", command_line(&command));
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(e) => {
+            log::debug!("Failed to execute nvidia-smi: {:?}", e);
+            return None;
+        },
+    };
+    log::debug!("Command output: {:?}", output);
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| {
+        let (name, driver_version) = line.split_once(',')?;
+        if !name.contains("GeForce") {
+            return None;
+        }
+        let driver_major = driver_version.trim().split('.').next()?.parse::<u32>().ok()?;
+        Some(match driver_major {
+            550.. => 8,
+            530.. => 5,
+            _ => 3,
+        })
+    }).min()
+}
+
+// One ab-av1 call's temp dir, removed however the call ends, as a failed search
+// otherwise leaves its samples behind, several GB for a long video
+struct SampleDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl SampleDir {
+    // named after the output, which is unique among concurrent encodes
+    fn create(output_path: impl AsRef<Path>, config: &Config) -> Result<Self> {
+        let output_path = output_path.as_ref();
+        let path = config.sample_video_dir().join(hash_file_location(output_path));
+        if path.exists() {
+            log::warn!("Removing leftover samples of an earlier run: {:?}", path);
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(Self { path, keep: config.keep_sample_files })
+    }
+}
+
+impl Drop for SampleDir {
+    fn drop(&mut self) {
+        if self.keep {
+            log::info!("Keeping samples in: {:?}", self.path);
+            return;
+        }
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            log::warn!("Failed to remove samples in {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+// Per-run state shared by every file of the batch
+struct RunContext<'a> {
+    opts: &'a AllOpts,
+    config: &'a Config,
+    inherited_log_level: String,
+    dedupe_index: DedupeIndex,
+    save_dir_index: Option<SaveDirIndex>,
+    catalog: Option<Catalog>,
+    // first part -> all parts in order, only the first part is queued
+    multiparts: HashMap<PathBuf, Vec<PathBuf>>,
+    save_sender: Option<SyncSender<SaveJob>>,
+}
+
+// Everything left to do for a video once its encodes are done, so that it can run on a save worker
+struct SaveJob {
+    video_path: PathBuf,
+    config: Config,
+    // (encoding_video_path, save_path, stats) of each successful encode
+    encoded_paths: Vec<(PathBuf, PathBuf, AbAv1Stats)>,
+    duration_secs: Option<f64>,
+    remove_original: bool,
+    failed_copy_path: Option<PathBuf>,
+    outcome: FileOutcome,
+    file_label: Option<String>,
+}
+
+// Files under dedupe_dirs indexed by size, content is fingerprinted only when sizes collide
+#[derive(Debug, Default)]
+struct DedupeIndex {
+    paths_by_size: HashMap<u64, Vec<PathBuf>>,
+}
+
+impl DedupeIndex {
+    fn build(dirs: &[PathBuf]) -> Result<Self> {
+        let mut paths_by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        for dir in dirs {
+            println!("Indexing dedupe directory: {}", dir.display());
+            for path in jdt::walk_dir(dir, |path| path) {
+                let size = fs::metadata(&path)?.len();
+                paths_by_size.entry(size).or_default().push(path);
+            }
+        }
+        Ok(Self { paths_by_size })
+    }
+
+    fn find_duplicate(&self, file_path: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+        let file_path = file_path.as_ref();
+        let size = fs::metadata(file_path)?.len();
+        let Some(candidate_paths) = self.paths_by_size.get(&size) else {
+            return Ok(None);
+        };
+        let fingerprint = content_fingerprint(file_path)?;
+        for candidate_path in candidate_paths {
+            if candidate_path != file_path && content_fingerprint(candidate_path)? == fingerprint {
+                return Ok(Some(candidate_path.clone()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+const SOURCE_FINGERPRINT_XATTR: &str = "user.batchav1.source_fingerprint";
+
+// Saved outputs tagged with the content fingerprint of their source, so they are found
+// wherever they were moved under save_dir, as long as the move kept extended attributes
+#[derive(Debug, Default)]
+struct SaveDirIndex {
+    paths_by_source_fingerprint: HashMap<String, PathBuf>,
+}
+
+impl SaveDirIndex {
+    fn build(save_dir: impl AsRef<Path>) -> Result<Self> {
+        let save_dir = save_dir.as_ref();
+        let mut paths_by_source_fingerprint = HashMap::new();
+        if !xattr::SUPPORTED_PLATFORM {
+            log::warn!("Extended attributes are not supported on this platform, not indexing save directory");
+            return Ok(Self { paths_by_source_fingerprint });
+        }
+        println!("Indexing save directory: {}", save_dir.display());
+        for path in jdt::walk_dir(save_dir, |path| path) {
+            match xattr::get(&path, SOURCE_FINGERPRINT_XATTR) {
+                Ok(Some(fingerprint)) => {
+                    paths_by_source_fingerprint.insert(String::from_utf8_lossy(&fingerprint).to_string(), path);
+                },
+                Ok(None) => {},
+                Err(e) => log::warn!("Failed to get source fingerprint of {:?}: {:?}", path, e),
+            }
+        }
+        Ok(Self { paths_by_source_fingerprint })
+    }
+
+    fn find_encoded(&self, video_path: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+        let video_path = video_path.as_ref();
+        if self.paths_by_source_fingerprint.is_empty() {
+            return Ok(None);
+        }
+        let fingerprint = content_fingerprint(video_path)?;
+        Ok(self.paths_by_source_fingerprint.get(&fingerprint).cloned())
+    }
+}
+
+// An external inventory, a CSV with a header row naming "name" and/or "fingerprint" columns,
+// or a JSON array of {"name": ..., "fingerprint": ...}. Names match the source or output file name
+// case-insensitively, fingerprints are content_fingerprint of the source
+#[derive(Debug, Default)]
+struct Catalog {
+    names: HashSet<String>,
+    fingerprints: HashSet<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CatalogEntry {
+    name: Option<String>,
+    fingerprint: Option<String>,
+}
+
+impl Catalog {
+    fn load(catalog_path: impl AsRef<Path>) -> Result<Self> {
+        let catalog_path = catalog_path.as_ref();
+        let content = fs::read_to_string(catalog_path)?;
+        let entries: Vec<CatalogEntry> = if catalog_path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")) {
+            serde_json::from_str(&content).map_err(|e| Error::InvalidCatalog(catalog_path.to_path_buf(), e.to_string()))?
+        } else {
+            let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+            let header = parse_csv_line(lines.next().unwrap_or_default());
+            let name_column = header.iter().position(|column| column.trim() == "name");
+            let fingerprint_column = header.iter().position(|column| column.trim() == "fingerprint");
+            if name_column.is_none() && fingerprint_column.is_none() {
+                return Err(anyhow!(Error::InvalidCatalog(catalog_path.to_path_buf(), "No name or fingerprint column in the header".to_string())));
+            }
+            lines.map(|line| {
+                let fields = parse_csv_line(line);
+                let field = |column: Option<usize>| column.and_then(|column| fields.get(column)).map(|field| field.trim().to_string()).filter(|field| !field.is_empty());
+                CatalogEntry { name: field(name_column), fingerprint: field(fingerprint_column) }
+            }).collect()
+        };
+
+        let mut catalog = Self::default();
+        for entry in entries {
+            if let Some(name) = entry.name {
+                catalog.names.insert(name.to_lowercase());
+            }
+            if let Some(fingerprint) = entry.fingerprint {
+                catalog.fingerprints.insert(fingerprint.to_lowercase());
+            }
+        }
+        println!("Loaded catalog with {} names and {} fingerprints: {}", catalog.names.len(), catalog.fingerprints.len(), catalog_path.display());
+        Ok(catalog)
+    }
+
+    // the fingerprint reads the source, so it's only taken when names don't match already
+    fn find(&self, video_path: impl AsRef<Path>, config: &Config) -> Result<Option<String>> {
+        let video_path = video_path.as_ref();
+        let video_filename = video_path.file_name().ok_or(Error::InvalidVideoPath(video_path.to_path_buf()))?;
+        let output_filename = destination_filename(video_filename, config)?;
+        for filename in [video_filename, output_filename.as_os_str()] {
+            let filename = filename.to_string_lossy().to_lowercase();
+            if self.names.contains(&filename) {
+                return Ok(Some(filename));
+            }
+        }
+        if !self.fingerprints.is_empty() {
+            let fingerprint = content_fingerprint(video_path)?;
+            if self.fingerprints.contains(&fingerprint) {
+                return Ok(Some(fingerprint));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// fields may be double-quoted to hold commas, with "" for a quote inside
+fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[derive(Serialize, Debug)]
+struct VideoProbe {
+    width: u32,
+    height: u32,
+    codec_name: Option<String>,
+    duration_secs: Option<f64>,
+    bit_rate: Option<u64>,
+    frame_rate: Option<f64>,
+    // width over height of a pixel, anamorphic DVDs store 16:9 and 4:3 in the same 720x480
+    sample_aspect_ratio: Option<f64>,
+    // clockwise degrees, one of 0, 90, 180 and 270
+    rotation: u32,
+}
+
+impl VideoProbe {
+    // baked rotations turn the frames before scaling, so portrait sources scale by their width
+    fn filter_height(&self, config: &Config) -> u32 {
+        if config.rotation_mode == RotationMode::Bake && self.rotation % 180 == 90 {
+            self.width
+        } else {
+            self.height
+        }
+    }
+
+    // as played, so a portrait phone video is below 1 whatever the stored frames look like
+    fn display_aspect_ratio(&self) -> f64 {
+        let aspect_ratio = self.width as f64 * self.sample_aspect_ratio.unwrap_or(1.0) / self.height as f64;
+        if self.rotation % 180 == 90 {
+            1.0 / aspect_ratio
+        } else {
+            aspect_ratio
+        }
+    }
+}
+
+// Subset of `ffprobe -of json` output, numbers in format are strings there
+#[derive(Deserialize, Debug, Default)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    format: Option<FfprobeFormat>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct FfprobeStream {
+    width: Option<u32>,
+    height: Option<u32>,
+    codec_name: Option<String>,
+    duration: Option<String>,
+    nb_frames: Option<String>,
+    r_frame_rate: Option<String>,
+    avg_frame_rate: Option<String>,
+    sample_aspect_ratio: Option<String>,
+    #[serde(default)]
+    side_data_list: Vec<FfprobeSideData>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+impl FfprobeStream {
+    // the display matrix turns counterclockwise, the legacy rotate tag clockwise
+    fn rotation(&self) -> u32 {
+        let degrees = self.side_data_list.iter().find_map(|side_data| side_data.rotation).map(|rotation| -rotation)
+            .or_else(|| self.tags.get("rotate").and_then(|rotate| rotate.parse::<i64>().ok()))
+            .unwrap_or(0);
+        degrees.rem_euclid(360) as u32
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct FfprobePrograms {
+    #[serde(default)]
+    programs: Vec<FfprobeProgram>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct FfprobeProgram {
+    program_id: u32,
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct FfprobeSideData {
+    rotation: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct FfprobeFormat {
+    bit_rate: Option<String>,
+    duration: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+    /// Never write into save directories, for auditing an archived library
+    #[clap(long, global = true)]
+    readonly_save: bool,
+    /// Save into this directory instead of save_dir and save_dir_overrides, for a one-off run
+    #[clap(long, global = true)]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+enum SubCommand {
+    All(AllOpts),
+    DebugSingle(DebugSingleOpts),
+    ForceCrfSingle(ForceCrfSingleOpts),
+    Diagnose(DiagnoseOpts),
+    TuneCrf(TuneOpts),
+    CrfSweep(SweepOpts),
+    EstimateSize(EstimateOpts),
+    Rename(RenameOpts),
+    AuditAndReencode(AuditOpts),
+    ImageSequence(SeqOpts),
+    SelfTest(SelfTestOpts),
+    History(HistoryOpts),
+    RunProfiles(ProfilesOpts),
+    Review(ReviewOpts),
+}
+
+#[derive(Parser, Debug)]
+struct AllOpts {
+    /// One or more directories, processed in order, a file under several of them only once
+    #[clap(required = true, num_args = 1..)]
+    video_dirs: Vec<PathBuf>,
+    target_vmaf: u8,
+    /// Stop processing new files after this duration, e.g. 6h, 90m, 1h30m
+    #[clap(long, value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+    /// Interrupt the running encode at max runtime instead of letting it finish
+    #[clap(long, requires = "max_runtime")]
+    interrupt_at_max_runtime: bool,
+    /// List junk files and ask once before deleting them, they are kept when not run in a terminal
+    #[clap(long)]
+    confirm_delete_junk: bool,
+    /// Re-encode videos already in the save directory, the old output is replaced only once the new one is saved
+    #[clap(long)]
+    force_reencode: bool,
+    /// Also write the encode commands run for each file into a shell script in this directory
+    #[clap(long)]
+    emit_scripts: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct DebugSingleOpts {
+    video_path: PathBuf,
+    target_vmaf: u8,
+    /// Defaults to "<name>.debug.<output_extension>" in save_dir
+    #[clap(long)]
+    output: Option<PathBuf>,
+    /// Saves ab-av1 stdout and stderr, to "<output>.log" when no path is given
+    #[clap(long)]
+    log_file: Option<Option<PathBuf>>,
+    /// Encode this video stream, the absolute index as ffprobe shows it, needs use_ab_av1_crf_with_ffmpeg
+    #[clap(long)]
+    video_stream: Option<usize>,
+    /// Keep only this audio stream, the absolute index as ffprobe shows it, needs use_ab_av1_crf_with_ffmpeg
+    #[clap(long)]
+    audio_stream: Option<usize>,
+}
+
+#[derive(Parser, Debug)]
+struct DiagnoseOpts {
+    video_dir: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct TuneOpts {
+    video_path: PathBuf,
+    crf: u8,
+    /// Length of the sample encoded for each crf
+    #[clap(long, default_value_t = 60.0)]
+    sample_secs: f64,
+    /// Start of the sample, defaults to the middle of the video
+    #[clap(long)]
+    sample_start: Option<f64>,
+}
+
+#[derive(Parser, Debug)]
+struct SweepOpts {
+    video_path: PathBuf,
+    /// Crf step from min_crf to max_crf
+    #[clap(long, default_value_t = 5)]
+    step: u8,
+    /// Length of the sample encoded for each crf
+    #[clap(long, default_value_t = 60.0)]
+    sample_secs: f64,
+    /// Start of the sample, defaults to the middle of the video
+    #[clap(long)]
+    sample_start: Option<f64>,
+    /// Write the results as CSV too
+    #[clap(long)]
+    csv: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct EstimateOpts {
+    video_path: PathBuf,
+    /// Defaults to the sidecar's crf, or the middle of min_crf and max_crf
+    #[clap(long)]
+    crf: Option<u8>,
+    /// Length of the sample encoded
+    #[clap(long, default_value_t = 60.0)]
+    sample_secs: f64,
+    /// Start of the sample, defaults to the middle of the video
+    #[clap(long)]
+    sample_start: Option<f64>,
+}
+
+#[derive(Parser, Debug)]
+struct AuditOpts {
+    video_dir: PathBuf,
+    /// Outputs measuring below this VMAF against their source are re-encoded
+    min_vmaf: f64,
+    /// Added to min_vmaf for the re-encode target, as the search mispredicted at the original target
+    #[clap(long, default_value_t = 2)]
+    bump: u8,
+}
+
+#[derive(Parser, Debug)]
+struct SeqOpts {
+    /// Frames as a glob on the file name, e.g. "renders/frame_*.png"
+    pattern: String,
+    framerate: f64,
+    crf: u8,
+    /// Relative paths are under save_dir
+    output_path: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct SelfTestOpts {
+    /// Length of the generated test clip
+    #[clap(long, default_value_t = 5)]
+    secs: u32,
+    /// Target VMAF for the ab-av1 check, low enough for any working setup
+    #[clap(long, default_value_t = 80)]
+    target_vmaf: u8,
+}
+
+#[derive(Parser, Debug)]
+struct HistoryOpts {
+    /// List the sources whose latest encode failed
+    #[clap(long)]
+    failures: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ReviewOpts {
+    #[clap(subcommand)]
+    action: ReviewAction,
+}
+
+#[derive(Parser, Debug)]
+enum ReviewAction {
+    /// Set sources aside, so that no run encodes them
+    Add {
+        #[clap(required = true, num_args = 1..)]
+        video_paths: Vec<PathBuf>,
+    },
+    /// Let sources be encoded again, given by path or by the hash listed
+    Remove {
+        #[clap(required = true, num_args = 1..)]
+        video_paths: Vec<PathBuf>,
+    },
+    List,
+}
+
+#[derive(Parser, Debug)]
+struct ProfilesOpts {
+    /// Defaults to profiles.toml next to config.toml
+    profiles_path: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct RenameOpts {
+    /// Print the renames without applying them
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ForceCrfSingleOpts {
+    video_path: PathBuf,
+    crf: u8,
+    /// Save here instead of save_dir, chapters with split_by_chapters are named after it
+    #[clap(long)]
+    output: Option<PathBuf>,
+    /// Encode this video stream, the absolute index as ffprobe shows it
+    #[clap(long)]
+    video_stream: Option<usize>,
+    /// Keep only this audio stream, the absolute index as ffprobe shows it
+    #[clap(long)]
+    audio_stream: Option<usize>,
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            exit_code_for_error(&e)
+        }
+    }
+}
+
+fn run() -> Result<ExitCode> {
+    // clap exits with 2 on usage errors, which collides with EXIT_FILE_FAILURES.
+    // Parsed before the config is loaded, so --help and --version work even with a broken config
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            e.print()?;
+            return Ok(if e.use_stderr() { ExitCode::from(EXIT_CONFIG_ERROR) } else { ExitCode::SUCCESS });
+        }
+    };
+
+    let mut config = jdt::project(crate_name!()).config::<Config>();
+    init_logger(&config)?;
+    log::debug!("Config: {:?}", config);
+    config.validate()?;
+    if let Some(max_concurrent_probes) = config.max_concurrent_probes {
+        PROBE_LIMIT.set(ProcessLimit::new(max_concurrent_probes)).expect("probe limit must be set only once");
+    }
+    config.readonly_save |= args.readonly_save;
+    // replaced before anything is derived from save_dir, so existing outputs are looked up there too
+    if let Some(output_dir) = args.output_dir {
+        config.save_dir = output_dir;
+        config.save_dir_overrides.clear();
+    }
+    match args.subcmd {
+        SubCommand::All(opts) => {
+            if config.skip_if_gpu_busy && gpu_busy() {
+                println!("GPU busy, nothing to do");
+                return Ok(ExitCode::SUCCESS);
+            }
+            let summary = run_all(opts, config)?;
+            if summary.has_failures() {
+                return Ok(ExitCode::from(EXIT_FILE_FAILURES));
+            }
+        },
+        SubCommand::DebugSingle(opts) => run_debug_single_command(opts, config)?,
+        SubCommand::ForceCrfSingle(opts) => run_force_crf_single_command(opts, config)?,
+        SubCommand::Diagnose(opts) => run_diagnose_command(opts, config)?,
+        SubCommand::TuneCrf(opts) => run_tune_crf_command(opts, config)?,
+        SubCommand::CrfSweep(opts) => run_crf_sweep_command(opts, config)?,
+        SubCommand::EstimateSize(opts) => run_estimate_size_command(opts, config)?,
+        SubCommand::Rename(opts) => run_rename_command(opts, config)?,
+        SubCommand::AuditAndReencode(opts) => run_audit_command(opts, config)?,
+        SubCommand::ImageSequence(opts) => run_image_sequence_command(opts, config)?,
+        SubCommand::SelfTest(opts) => run_self_test_command(opts, config)?,
+        SubCommand::History(opts) => run_history_command(opts, config)?,
+        SubCommand::Review(opts) => run_review_command(opts, config)?,
+        SubCommand::RunProfiles(opts) => {
+            if run_profiles_command(opts, config)? {
+                return Ok(ExitCode::from(EXIT_FILE_FAILURES));
+            }
+        },
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn exit_code_for_error(e: &anyhow::Error) -> ExitCode {
+    match e.downcast_ref::<Error>() {
+        Some(Error::UnsupportedEncoder(_) | Error::UnsupportedContainerCodec(..) | Error::UnsupportedContainerAudioCodec(..) | Error::AudioEncoderNotAvailable(_) | Error::RenamerCommandNotFound(_) | Error::HistoryDbNotConfigured | Error::ReviewListNotConfigured | Error::InvalidCatalog(..) | Error::InvalidProfiles(..) | Error::InvalidReferenceSizes(..) | Error::InvalidMultipartPattern(..) | Error::InvalidAspectRatio(_) | Error::StreamSelectionWithoutFfmpeg) => ExitCode::from(EXIT_CONFIG_ERROR),
+        Some(Error::MissingDependency(_)) => ExitCode::from(EXIT_MISSING_DEPENDENCY),
+        Some(Error::Interrupted) => ExitCode::from(EXIT_INTERRUPTED),
+        _ => ExitCode::FAILURE,
+    }
+}
+
+fn run_all(opts: AllOpts, config: Config) -> Result<RunSummary> {
+    install_signal_handlers();
+    check_audio_encoders(&config)?;
+
+    let start_time = Instant::now();
+    let deadline = opts.max_runtime.map(|max_runtime| start_time + max_runtime);
+    if let Some(deadline) = deadline {
+        if opts.interrupt_at_max_runtime {
+            INTERRUPT_DEADLINE.set(deadline).expect("interrupt deadline must be set only once");
+        }
+    }
+
+    // collected up front so that workers can share one queue
+    let mut seen_paths = HashSet::new();
+    let mut video_paths = Vec::new();
+    for video_dir in &opts.video_dirs {
+        for video_path in jdt::walk_dir(video_dir, |path| path) {
+            // nested or symlinked roots reach the same file by different paths
+            if seen_paths.insert(fs::canonicalize(&video_path).unwrap_or(video_path.clone())) {
+                video_paths.push(video_path);
+            }
+        }
+    }
+    let video_paths = if config.expand_disc_images { collapse_disc_dirs(video_paths) } else { video_paths };
+    let (video_paths, multiparts) = if config.merge_multipart {
+        let multipart_pattern = Regex::new(&config.multipart_pattern).map_err(|e| Error::InvalidMultipartPattern(config.multipart_pattern.clone(), e.to_string()))?;
+        group_multipart_videos(video_paths, &multipart_pattern)
+    } else {
+        (video_paths, HashMap::new())
+    };
+
+    let inherited_log_level = env::var("RUST_LOG").unwrap_or("warn".to_string());
+    log::debug!("Inherited log level: {}", inherited_log_level);
+
+    // the bounded queue also bounds how many finished encodes wait in the temp dir
+    let (save_sender, save_receiver) = if config.save_concurrency > 0 {
+        let (save_sender, save_receiver) = mpsc::sync_channel(config.save_concurrency);
+        (Some(save_sender), Some(Mutex::new(save_receiver)))
+    } else {
+        (None, None)
+    };
+
+    let ctx = RunContext {
+        opts: &opts,
+        config: &config,
+        inherited_log_level,
+        dedupe_index: DedupeIndex::build(&config.dedupe_dirs)?,
+        save_dir_index: if config.skip_encoded_by_fingerprint { Some(SaveDirIndex::build(&config.save_dir)?) } else { None },
+        catalog: config.catalog.as_ref().map(Catalog::load).transpose()?,
+        multiparts,
+        save_sender,
+    };
+
+    // run-profiles calls this once per profile, and they share the first history_db
+    if let (Some(history_db), None) = (&config.history_db, HISTORY_DB.get()) {
+        HISTORY_DB.set(HistoryDb::open(history_db)?).expect("history db must be set only once");
+    }
+    if let (Some(review_list), None) = (&config.review_list, REVIEW_LIST.get()) {
+        REVIEW_LIST.set(ReviewList::load(review_list)?).expect("review list must be set only once");
+    }
+    if let (Some(reference_sizes), None) = (&config.reference_sizes, REFERENCE_SIZES.get()) {
+        REFERENCE_SIZES.set(load_reference_sizes(reference_sizes)?).expect("reference sizes must be set only once");
+    }
+    if let Some(emit_scripts) = &opts.emit_scripts {
+        fs::create_dir_all(emit_scripts)?;
+    }
+
+    let concurrency = config.concurrency.resolve(&config.encoder);
+    log::debug!("Concurrency: {}", concurrency);
+    let parallel = concurrency > 1 || config.save_concurrency > 0;
+    // bars draw to stderr and drop printed lines when it isn't a terminal, so both must be
+    if parallel && std::io::stdout().is_terminal() && std::io::stderr().is_terminal() {
+        MULTI_PROGRESS.get_or_init(MultiProgress::new);
+    }
+
+    let mut summary = RunSummary::default();
+    let video_paths = if opts.confirm_delete_junk { confirm_delete_junk(video_paths, &mut summary)? } else { video_paths };
+
+    let summary = Mutex::new(summary);
+    let status_file = config.status_file.as_ref().map(|path| StatusFile::start(path, config.status_interval_secs)).transpose()?;
+    let video_count = video_paths.len();
+    let video_paths = Mutex::new(video_paths.into_iter().enumerate());
+    let stopped = AtomicBool::new(false);
+    // (encodes in the current batch, end of the pause after it), shared so that no worker starts a file during the pause
+    let batch_state = Mutex::new((0, None));
+    // counted across workers, as a systemic problem fails every worker's files alike,
+    // queued encodes are counted by the save worker that knows how they ended
+    let consecutive_failures = Mutex::new(0);
+    let count_outcome = |outcome: FileOutcome| -> Result<()> {
+        let mut consecutive_failures = consecutive_failures.lock().expect("failure count lock must not be poisoned");
+        match outcome {
+            FileOutcome::Failed => *consecutive_failures += 1,
+            FileOutcome::Encoded | FileOutcome::Salvaged => *consecutive_failures = 0,
+            _ => return Ok(()),
+        }
+        match config.max_consecutive_failures {
+            Some(max_consecutive_failures) if *consecutive_failures >= max_consecutive_failures => {
+                stopped.store(true, Ordering::SeqCst);
+                Err(anyhow!(Error::TooManyConsecutiveFailures(*consecutive_failures)))
+            },
+            _ => Ok(()),
+        }
+    };
+
+    let worker = |worker_index: usize| -> Result<()> {
+        if !config.cpu_affinity.is_empty() {
+            let cores = &config.cpu_affinity[worker_index % config.cpu_affinity.len()];
+            log::debug!("Worker {} pinned to cores {:?}", worker_index, cores);
+            pin_current_thread(cores);
+        }
+        if !config.cuda_devices.is_empty() {
+            let cuda_device = config.cuda_devices[worker_index % config.cuda_devices.len()];
+            log::debug!("Worker {} encodes on CUDA device {}", worker_index, cuda_device);
+            CUDA_DEVICE.with(|current| *current.borrow_mut() = Some(cuda_device));
+        }
+        while !stopped.load(Ordering::SeqCst) {
+            if let Some(control_file) = &config.control_file {
+                wait_while_paused(control_file);
+            }
+            let batch_pause_until = batch_state.lock().expect("batch lock must not be poisoned").1;
+            if let Some(batch_pause_until) = batch_pause_until {
+                wait_for_batch_pause(batch_pause_until);
+            }
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                stopped.store(true, Ordering::SeqCst);
+                return Err(anyhow!(Error::Interrupted));
+            }
+            let Some((index, video_path)) = video_paths.lock().expect("queue lock must not be poisoned").next() else {
+                break;
+            };
+            if parallel {
+                set_file_label(Some(format!("[file {}/{}]", index + 1, video_count)));
+            }
+            log::trace!("Iterate path: {}", video_path.display());
+            if let Some(emit_scripts) = &opts.emit_scripts {
+                set_encode_script(Some(encode_script_path(emit_scripts, &video_path)?));
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                stopped.store(true, Ordering::SeqCst);
+                let remaining = video_paths.lock().expect("queue lock must not be poisoned").by_ref().count() + 1;
+                report!("Max runtime exceeded, stopping with {} files remaining", remaining);
+                break;
+            }
+
+            if let Some(max_gpu_temp_c) = config.max_gpu_temp_c {
+                wait_for_gpu_cooldown(max_gpu_temp_c);
+            }
+
+            if let Some(status_file) = &status_file {
+                status_file.update(index, Some(&video_path))?;
+            }
+
+            let result = if config.expand_disc_images && is_disc_image(&video_path) {
+                process_disc_image(&video_path, &ctx)
+            } else if let Some(parts) = ctx.multiparts.get(&video_path) {
+                process_multipart(parts, &ctx)
+            } else {
+                process_video(&video_path, &[], &ctx)
+            };
+            match result {
+                Ok(outcome) => {
+                    // skipped files don't heat the GPU, so only encodes count toward a batch
+                    if let (Some(batch_size), Some(batch_pause_secs)) = (config.batch_size, config.batch_pause_secs) {
+                        if matches!(outcome, FileOutcome::Encoded | FileOutcome::Salvaged | FileOutcome::Failed | FileOutcome::Incompressible | FileOutcome::Queued) {
+                            let mut batch_state = batch_state.lock().expect("batch lock must not be poisoned");
+                            batch_state.0 += 1;
+                            if batch_state.0 >= batch_size {
+                                report!("Finished a batch of {} encodes, pausing {} sec", batch_state.0, batch_pause_secs);
+                                *batch_state = (0, Some(Instant::now() + Duration::from_secs(batch_pause_secs)));
+                            }
+                        }
+                    }
+                    record_history_file(&video_path, outcome);
+                    summary.lock().expect("summary lock must not be poisoned").record(&video_path, outcome);
+                    count_outcome(outcome)?;
+                },
+                Err(e) => {
+                    match e.downcast_ref::<Error>() {
+                        Some(Error::MaxRuntimeExceeded) => {
+                            stopped.store(true, Ordering::SeqCst);
+                            let remaining = video_paths.lock().expect("queue lock must not be poisoned").by_ref().count() + 1;
+                            report!("Max runtime exceeded, interrupted encoding with {} files remaining: {}", remaining, video_path.display());
+                            break;
+                        },
+                        Some(Error::Interrupted) => {
+                            stopped.store(true, Ordering::SeqCst);
+                            report!("Interrupted, partial encoding removed: {}", video_path.display());
+                            return Err(e);
+                        },
+                        Some(Error::SaveDirNotWritable(_)) if !config.abort_on_unwritable_save_dir => {
+                            log::error!("Skipping video {:?}: {}", video_path, e);
+                            report!("Skipping video {}: {}", video_path.display(), e);
+                            summary.lock().expect("summary lock must not be poisoned").skipped_fatal.push((video_path, e.to_string()));
+                        },
+                        Some(Error::ConflictVideoEncoding(..) | Error::ConflictFailedCopyPath(..) | Error::FoundInvalidVideoFileInSavedPath(..)) if config.continue_on_fatal => {
+                            log::error!("Skipping video {:?} on fatal error: {}", video_path, e);
+                            report!("Skipping video {} on fatal error: {}", video_path.display(), e);
+                            summary.lock().expect("summary lock must not be poisoned").skipped_fatal.push((video_path, e.to_string()));
+                            count_outcome(FileOutcome::Failed)?;
+                        },
+                        _ => {
+                            stopped.store(true, Ordering::SeqCst);
+                            return Err(e);
+                        },
+                    }
+                }
+            }
+        }
+        Ok(())
+    };
+
+    let encoding_done = AtomicBool::new(false);
+    // keeps draining the queue even after an error, as a dropped job would leave its encode in the temp dir
+    let save_worker = |save_receiver: &Mutex<Receiver<SaveJob>>| -> Result<()> {
+        let mut first_error = None;
+        loop {
+            let received = save_receiver.lock().expect("save queue lock must not be poisoned").recv_timeout(Duration::from_millis(200));
+            let job = match received {
+                Ok(job) => job,
+                Err(RecvTimeoutError::Timeout) if encoding_done.load(Ordering::SeqCst) => break,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let video_path = job.video_path.clone();
+            set_file_label(job.file_label.clone());
+            match save_encoded_video(job) {
+                Ok(outcome) => {
+                    record_history_file(&video_path, outcome);
+                    summary.lock().expect("summary lock must not be poisoned").record(&video_path, outcome);
+                    if let Err(e) = count_outcome(outcome) {
+                        first_error.get_or_insert(e);
+                    }
+                },
+                Err(e) => {
+                    stopped.store(true, Ordering::SeqCst);
+                    log::error!("Failed to save video {:?}: {}", video_path, e);
+                    first_error.get_or_insert(e);
+                },
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    };
+
+    let results: Vec<Result<()>> = thread::scope(|scope| {
+        let save_worker = &save_worker;
+        let save_handles: Vec<_> = match &save_receiver {
+            Some(save_receiver) => (0..config.save_concurrency).map(|_| scope.spawn(move || save_worker(save_receiver))).collect(),
+            None => Vec::new(),
+        };
+        let worker = &worker;
+        let handles: Vec<_> = (0..concurrency).map(|worker_index| scope.spawn(move || worker(worker_index))).collect();
+        let mut results: Vec<_> = handles.into_iter().map(|handle| handle.join().expect("worker must not panic")).collect();
+        encoding_done.store(true, Ordering::SeqCst);
+        results.extend(save_handles.into_iter().map(|handle| handle.join().expect("save worker must not panic")));
+        results
+    });
+    for result in results {
+        result?;
+    }
+
+    if let Some(status_file) = &status_file {
+        status_file.finish()?;
+    }
+
+    let summary = summary.into_inner().expect("summary lock must not be poisoned");
+    summary.print();
+
+    Ok(summary)
+}
+
+// takes junk out of the batch, so process_video never deletes it without the answer
+fn confirm_delete_junk(video_paths: Vec<PathBuf>, summary: &mut RunSummary) -> Result<Vec<PathBuf>> {
+    let (junk_paths, video_paths): (Vec<PathBuf>, Vec<PathBuf>) = video_paths.into_iter().partition(|path| is_junk(path));
+    if junk_paths.is_empty() {
+        return Ok(video_paths);
+    }
+
+    println!("Junk files:");
+    for junk_path in &junk_paths {
+        println!("  {}", junk_path.display());
+    }
+
+    let confirmed = if std::io::stdin().is_terminal() {
+        print!("Delete {} junk files? [y/N]: ", junk_paths.len());
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        matches!(line.trim(), "y" | "Y" | "yes")
+    } else {
+        println!("Not running in a terminal, keeping {} junk files", junk_paths.len());
+        false
+    };
+
+    let junk_count = junk_paths.len();
+    for junk_path in junk_paths {
+        if confirmed {
+            fs::remove_file(&junk_path)?;
+            summary.record(&junk_path, FileOutcome::Removed);
+        } else {
+            summary.record(&junk_path, FileOutcome::Skipped);
+        }
+    }
+    println!("{} {} junk files", if confirmed { "Removed" } else { "Kept" }, junk_count);
+    Ok(video_paths)
+}
+
+// files inside a VIDEO_TS directory are read through the directory as a whole
+fn collapse_disc_dirs(video_paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut collapsed = Vec::new();
+    for video_path in video_paths {
+        let video_path = match video_path.parent() {
+            Some(parent) if is_disc_image(parent) => parent.to_path_buf(),
+            _ => video_path,
+        };
+        if seen.insert(video_path.clone()) {
+            collapsed.push(video_path);
+        }
+    }
+    collapsed
+}
+
+fn is_disc_image(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    if path.is_dir() {
+        path.file_name().is_some_and(|name| name.eq_ignore_ascii_case("VIDEO_TS"))
+    } else {
+        path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("iso"))
+    }
+}
+
+// Keeps numbered parts together, e.g. "movie.part1.mkv" and "movie.part2.mkv" in one directory, only when
+// they run from 1 without gaps, as anything else is more likely unrelated files than a split archive
+fn group_multipart_videos(video_paths: Vec<PathBuf>, multipart_pattern: &Regex) -> (Vec<PathBuf>, HashMap<PathBuf, Vec<PathBuf>>) {
+    let mut groups: HashMap<PathBuf, Vec<(u32, PathBuf)>> = HashMap::new();
+    for video_path in &video_paths {
+        let Some(filename) = video_path.file_name().and_then(|filename| filename.to_str()) else {
+            continue;
+        };
+        let Some((base_filename, part_number)) = split_multipart_filename(filename, multipart_pattern) else {
+            continue;
+        };
+        groups.entry(video_path.with_file_name(base_filename)).or_default().push((part_number, video_path.clone()));
+    }
+
+    let mut multiparts = HashMap::new();
+    let mut merged_parts = HashSet::new();
+    for (base_path, mut parts) in groups {
+        if parts.len() < 2 {
+            continue;
+        }
+        parts.sort();
+        if !parts.iter().enumerate().all(|(index, (part_number, _))| *part_number as usize == index + 1) {
+            log::warn!("Not merging parts of {:?}, as they aren't numbered 1 to {}: {:?}", base_path, parts.len(), parts);
+            continue;
+        }
+        let parts: Vec<PathBuf> = parts.into_iter().map(|(_, part_path)| part_path).collect();
+        log::debug!("Multipart video {:?}: {:?}", base_path, parts);
+        merged_parts.extend(parts[1..].iter().cloned());
+        multiparts.insert(parts[0].clone(), parts);
+    }
+
+    let video_paths = video_paths.into_iter().filter(|video_path| !merged_parts.contains(video_path)).collect();
+    (video_paths, multiparts)
+}
+
+// "movie.part2.mkv" -> ("movie.mkv", 2), only the first match is cut, so a name matching twice keeps the later one
+fn split_multipart_filename(filename: &str, multipart_pattern: &Regex) -> Option<(String, u32)> {
+    let captures = multipart_pattern.captures(filename)?;
+    let whole = captures.get(0)?;
+    let part_number = captures.get(1)?.as_str().parse::<u32>().ok()?;
+    Some((format!("{}{}", &filename[..whole.start()], &filename[whole.end()..]), part_number))
+}
+
+// the same name group_multipart_videos grouped the parts under
+fn multipart_base_path(first_part: impl AsRef<Path>, multipart_pattern: &str) -> Result<PathBuf> {
+    let first_part = first_part.as_ref();
+    let multipart_pattern = Regex::new(multipart_pattern).map_err(|e| Error::InvalidMultipartPattern(multipart_pattern.to_string(), e.to_string()))?;
+    let filename = first_part.file_name().and_then(|filename| filename.to_str()).ok_or(Error::InvalidVideoPath(first_part.to_path_buf()))?;
+    let (base_filename, _) = split_multipart_filename(filename, &multipart_pattern).ok_or(Error::InvalidVideoPath(first_part.to_path_buf()))?;
+    Ok(first_part.with_file_name(base_filename))
+}
+
+// Concatenates the parts into the temp dir and encodes the result as if it were the source,
+// the merged file is named after the base name so that the output is too
+fn process_multipart(parts: &[PathBuf], ctx: &RunContext) -> Result<FileOutcome> {
+    let config = ctx.config;
+    let first_part = &parts[0];
+    let base_path = multipart_base_path(first_part, &config.multipart_pattern)?;
+
+    let mut base_config = config.clone();
+    base_config.output_extension = base_config.output_extension_for(&base_path);
+    if encoded_file_save_path(&base_path, &base_config)?.exists() && !ctx.opts.force_reencode {
+        report!("Skipping multipart video {} as it already exists in save directory", base_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    let merged_dir = config.encoding_video_dir().join(format!("multipart-{}", hash_file_location(first_part)));
+    let merged_path = merged_dir.join(base_path.file_name().ok_or(Error::InvalidVideoPath(base_path.clone()))?);
+    if merged_dir.exists() {
+        fs::remove_dir_all(&merged_dir)?;
+    }
+    fs::create_dir_all(&merged_dir)?;
+
+    report!("Merging {} parts into: {}", parts.len(), merged_path.display());
+    if let Err(e) = exec_concat_ffmpeg(parts, &merged_path) {
+        fs::remove_dir_all(&merged_dir)?;
+        if matches!(e.downcast_ref::<Error>(), Some(Error::ConcatFfmpegCommandFailed(_))) {
+            report!("Failed to merge parts of {}: {}", base_path.display(), e);
+            return Ok(FileOutcome::Failed);
+        }
+        return Err(e);
+    }
+
+    // a missing or truncated part still concatenates fine, only the duration gives it away
+    let mut parts_secs = 0.0;
+    for part_path in parts {
+        parts_secs += rough_video_secs(part_path, config)?.unwrap_or(0.0);
+    }
+    let merged_secs = rough_video_secs(&merged_path, config)?.unwrap_or(0.0);
+    if (merged_secs - parts_secs).abs() > parts.len() as f64 {
+        log::warn!("Merged duration of {:?} is {:.2} sec, parts sum to {:.2} sec", merged_path, merged_secs, parts_secs);
+        report!("Merged duration {:.2} sec doesn't match parts total {:.2} sec: {}", merged_secs, parts_secs, base_path.display());
+        fs::remove_dir_all(&merged_dir)?;
+        return Ok(FileOutcome::Failed);
+    }
+
+    let result = process_video(&merged_path, parts, ctx);
+    // a queued save removes the merged source itself once it's done with it
+    if !matches!(result, Ok(FileOutcome::Queued)) && merged_dir.exists() {
+        fs::remove_dir_all(&merged_dir)?;
+    }
+    result
+}
+
+fn exec_concat_ffmpeg(parts: &[PathBuf], output_path: impl AsRef<Path>) -> Result<()> {
+    let output_path = output_path.as_ref();
+    // the concat demuxer reads a list of quoted paths, a quote in a path is closed, escaped and reopened
+    let list_path = output_path.with_extension("concat.txt");
+    let list = parts.iter()
+        .map(|part_path| fs::canonicalize(part_path).map(|part_path| format!("file '{}'\n", part_path.to_string_lossy().replace('\'', r"'\''"))))
+        .collect::<std::io::Result<String>>()?;
+    fs::write(&list_path, list)?;
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-y")
+        .arg("-f").arg("concat")
+        .arg("-safe").arg("0")
+        .arg("-i").arg(&list_path)
+        .arg("-map").arg("0")
+        .arg("-c").arg("copy")
+        .arg(output_path);
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, anyhow::Error::from))?;
+    log::debug!("Command output: {:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::ConcatFfmpegCommandFailed(output.status)));
+    }
+    Ok(())
+}
+
+fn remove_merged_multipart(merged_path: impl AsRef<Path>, config: &Config) -> Result<()> {
+    let merged_path = merged_path.as_ref();
+    if config.multipart_sources.is_empty() {
+        return Ok(());
+    }
+    if let Some(merged_dir) = merged_path.parent().filter(|merged_dir| merged_dir.exists()) {
+        fs::remove_dir_all(merged_dir)?;
+    }
+    Ok(())
+}
+
+// ab-av1 can't read discs, so these go through force crf with the crf from the sidecar,
+// and the source is always kept as a rip is usually more than just the main title
+fn process_disc_image(disc_path: &Path, ctx: &RunContext) -> Result<FileOutcome> {
+    let sidecar = Sidecar::load(disc_path)?.unwrap_or_default();
+    if sidecar.skip {
+        report!("Skipping disc image {} as its sidecar says so", disc_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+    let mut sidecar_config = sidecar.apply(ctx.config)?;
+    sidecar_config.output_extension = sidecar_config.output_extension_for(disc_path);
+    // the grain probe can't read discs, so only flagged ones are denoised
+    if sidecar.denoise != Some(true) {
+        sidecar_config.denoise = None;
+    }
+    let config = &sidecar_config;
+    let Some(crf) = sidecar.crf else {
+        report!("Skipping disc image {} as it needs crf in its sidecar", disc_path.display());
+        return Ok(FileOutcome::Skipped);
+    };
+
+    // VIDEO_TS is named after its parent directory
+    let title_path = if disc_path.is_dir() { disc_path.parent().unwrap_or(disc_path) } else { disc_path };
+    let save_path = encoded_file_save_path(title_path, config)?;
+    create_save_dir(encoded_file_save_dir(title_path, config), config)?;
+    if save_path.exists() && !ctx.opts.force_reencode {
+        report!("Skipping disc image {} as it already exists in save directory", disc_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    let encodnig_video_dir = config.encoding_video_dir_for(encoded_file_save_dir(title_path, config));
+    fs::create_dir_all(&encodnig_video_dir)?;
+    let encoding_video_path = encodnig_video_dir.join(hash_file_location(disc_path)).with_extension(&config.output_extension);
+    if encoding_video_path.exists() {
+        return Err(anyhow!(Error::ConflictVideoEncoding(disc_path.to_path_buf(), encoding_video_path)));
+    }
+
+    report!("Encoding disc image: {}", disc_path.display());
+    let result = exec_force_crf_ffmpeg(disc_path, &encoding_video_path, crf, None, None, config);
+    let interrupted = INTERRUPTED.load(Ordering::SeqCst);
+    if (result.is_err() || interrupted) && encoding_video_path.exists() {
+        fs::remove_file(&encoding_video_path)?;
+    }
+    match result {
+        Ok(()) if interrupted => return Err(anyhow!(Error::Interrupted)),
+        Ok(()) => {},
+        Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::ForceCrfFfmpegCommandFailed(_))) => return Ok(FileOutcome::Failed),
+        Err(e) => return Err(e),
+    }
+
+    if !is_valid_video_file(&encoding_video_path, config)? {
+        log::warn!("Encoding failed for {:?}: Invalid video file", disc_path);
+        fs::remove_file(&encoding_video_path)?;
+        return Ok(FileOutcome::Failed);
+    }
+
+    if let Err(e) = check_output_duration(&encoding_video_path, config) {
+        if !matches!(e.downcast_ref::<Error>(), Some(Error::ZeroDurationOutput(_))) {
+            return Err(e);
+        }
+        log::warn!("Encoding failed for {:?}: {}", disc_path, e);
+        fs::remove_file(&encoding_video_path)?;
+        return Ok(FileOutcome::Failed);
+    }
+
+    report!("Saving video to: {}", save_path.display());
+    if save_path.exists() {
+        replace_in_save_dir(&encoding_video_path, &save_path, config)?;
+    } else {
+        rename_into_save_dir(&encoding_video_path, &save_path, config)?;
+    }
+    Ok(FileOutcome::Encoded)
+}
+
+const CONTROL_FILE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlDirective {
+    Pause,
+    Resume,
+    // missing, empty or unknown content, keeps the current state
+    None,
+}
+
+fn read_control_directive(control_file: impl AsRef<Path>) -> ControlDirective {
+    let control_file = control_file.as_ref();
+    let Ok(control_str) = fs::read_to_string(control_file) else {
+        return ControlDirective::None;
+    };
+    match control_str.trim() {
+        "pause" => ControlDirective::Pause,
+        "resume" => ControlDirective::Resume,
+        _ => ControlDirective::None,
+    }
+}
+
+// "pause" in the control file holds the batch between files until it says "resume" or disappears
+fn wait_while_paused(control_file: impl AsRef<Path>) {
+    let control_file = control_file.as_ref();
+    if read_control_directive(control_file) != ControlDirective::Pause {
+        return;
+    }
+
+    log::info!("Paused by control file {:?}", control_file);
+    report!("Paused by control file, write \"resume\" to continue: {}", control_file.display());
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        thread::sleep(CONTROL_FILE_POLL_INTERVAL);
+        if !control_file.exists() || read_control_directive(control_file) == ControlDirective::Resume {
+            log::info!("Resumed by control file {:?}", control_file);
+            report!("Resumed by control file: {}", control_file.display());
+            break;
+        }
+    }
+}
+
+const BATCH_PAUSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// sleeps in short steps so that SIGINT doesn't wait for the whole pause
+fn wait_for_batch_pause(batch_pause_until: Instant) {
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= batch_pause_until {
+            break;
+        }
+        thread::sleep(BATCH_PAUSE_POLL_INTERVAL.min(batch_pause_until - now));
+    }
+}
+
+const GPU_COOLDOWN_MARGIN_C: u32 = 5;
+const GPU_TEMP_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// resumes only once the GPU is a margin below the limit, so it doesn't flap around the threshold
+fn wait_for_gpu_cooldown(max_gpu_temp_c: u32) {
+    let Some(temp_c) = gpu_temp_c() else {
+        return;
+    };
+    if temp_c <= max_gpu_temp_c {
+        return;
+    }
+
+    let resume_temp_c = max_gpu_temp_c.saturating_sub(GPU_COOLDOWN_MARGIN_C);
+    log::warn!("GPU temperature {}°C exceeds {}°C, pausing until it drops to {}°C", temp_c, max_gpu_temp_c, resume_temp_c);
+    report!("GPU temperature {}°C exceeds {}°C, pausing until it drops to {}°C", temp_c, max_gpu_temp_c, resume_temp_c);
+    let pause_start = Instant::now();
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        thread::sleep(GPU_TEMP_POLL_INTERVAL);
+        match gpu_temp_c() {
+            Some(temp_c) if temp_c > resume_temp_c => log::debug!("GPU temperature: {}°C", temp_c),
+            _ => break,
+        }
+    }
+    report!("Resumed after pausing {:.0} sec for GPU cooldown", pause_start.elapsed().as_secs_f64());
+}
+
+// None when nvidia-smi is unavailable, the hottest GPU otherwise
+fn gpu_temp_c() -> Option<u32> {
+    let mut command = Command::new("nvidia-smi");
+    command
+        .arg("--query-gpu=temperature.gpu")
+        .arg("--format=csv,noheader,nounits");
+    log::debug!("Command: {}", command_line(&command));
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(e) => {
+            log::debug!("Failed to execute nvidia-smi: {:?}", e);
+            return None;
+        },
+    };
+    log::debug!("Command output: {:?}", output);
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| line.trim().parse::<u32>().ok()).max()
+}
+
+const GPU_BUSY_UTILIZATION_PERCENT: u32 = 90;
+
+// busy when any GPU has no memory left or is mostly utilized, not busy when nvidia-smi can't tell
+fn gpu_busy() -> bool {
+    let mut command = Command::new("nvidia-smi");
+    command
+        .arg("--query-gpu=memory.free,utilization.gpu")
+        .arg("--format=csv,noheader,nounits");
+    log::debug!("Command: {}", command_line(&command));
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(e) => {
+            log::debug!("Failed to execute nvidia-smi: {:?}", e);
+            return false;
+        },
+    };
+    log::debug!("Command output: {:?}", output);
+    if !output.status.success() {
+        return false;
+    }
+    String::from_utf8_lossy(&output.stdout).lines().any(|line| {
+        let mut values = line.split(',').map(|value| value.trim().parse::<u32>().ok());
+        let (memory_free_mib, utilization_percent) = (values.next().flatten(), values.next().flatten());
+        memory_free_mib == Some(0) || utilization_percent.is_some_and(|utilization_percent| utilization_percent >= GPU_BUSY_UTILIZATION_PERCENT)
+    })
+}
+
+fn process_video(video_path: &Path, multipart_sources: &[PathBuf], ctx: &RunContext) -> Result<FileOutcome> {
+    let sidecar = Sidecar::load(video_path)?.unwrap_or_default();
+    if sidecar.skip {
+        report!("Skipping video {} as its sidecar says so", video_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+    let mut sidecar_config = sidecar.apply(ctx.config)?;
+    sidecar_config.output_extension = sidecar_config.output_extension_for(video_path);
+    if sidecar_config.hls.is_some() {
+        // both the encoding and the saved output are directories named like "<name>.hls"
+        sidecar_config.output_extension = HLS_OUTPUT_EXTENSION.to_string();
+    }
+    if sidecar_config.record_source_path {
+        // a merged source lives in the temp dir, its first part is where it came from
+        let source_path = multipart_sources.first().map_or(video_path, |first_part| first_part.as_path());
+        sidecar_config.output_metadata.extend(source_path_metadata(source_path, &ctx.opts.video_dirs));
+    }
+    sidecar_config.multipart_sources = multipart_sources.to_vec();
+    let config = &sidecar_config;
+    let target_vmaf = sidecar.target_vmaf.unwrap_or(ctx.opts.target_vmaf);
+    let encodnig_video_dir = config.encoding_video_dir_for(encoded_file_save_dir(video_path, config));
+    let default_save_dir = &config.save_dir;
+    let save_dir_overrides = &config.save_dir_overrides;
+
+    let move_failed_files = config.move_failed_files;
+    let delete_almost_same_files = config.delete_almost_same_files;
+
+    fs::create_dir_all(&encodnig_video_dir)?;
+
+    // file_stem sometimes treats the last part of the file name as extension
+    // so we impl the way below
+    let video_location_hash = hash_file_location(video_path);
+    let encoding_video_path = encodnig_video_dir.join(&video_location_hash).with_extension(&config.output_extension);
+    let save_path = encoded_file_save_path(video_path, config)?;
+
+    let original_filename = video_path.file_name().ok_or(Error::InvalidVideoPath(video_path.to_path_buf()))?;
+    let save_dir = save_dir_for_filename(&original_filename, &default_save_dir, &save_dir_overrides);
+    create_save_dir(&save_dir, config)?;
+    create_save_dir(encoded_file_save_dir(video_path, config), config)?;
+    log::debug!("Save dir: ({}, {}, {:?}) -> {}", &original_filename.to_string_lossy().to_string(), default_save_dir.display(), save_dir_overrides, save_dir.display());
+
+    let dst_video_filename = rename_for_linux_limit::new_filename(video_path, Some(&save_dir))?;
+    let failed_copy_path = save_dir.join(dst_video_filename);
+
+    if is_junk(video_path) {
+        report!("Removing junk file: {}", video_path.display());
+        fs::remove_file(video_path)?;
+        return Ok(FileOutcome::Removed);
+    }
+
+    if !guess_video_file(video_path) {
+        report!("Skipping non-video file: {}", video_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    if !is_valid_video_file(video_path, config)? {
+        report!("Skipping invalid video file: {}", video_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    if config.done_marker.as_ref().is_some_and(|done_marker| has_done_marker(video_path, done_marker)) {
+        report!("Skipping video {} as it has the done marker", video_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    if REVIEW_LIST.get().is_some_and(|review_list| review_list.contains(video_path)) {
+        report!("Skipping video {} as it is on the review list", video_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    if let Some(duplicate_path) = ctx.dedupe_index.find_duplicate(video_path)? {
+        report!("Skipping video {} as the same content already exists: {}", video_path.display(), duplicate_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    if let Some(encoded_path) = ctx.save_dir_index.as_ref().filter(|_| !ctx.opts.force_reencode).map(|save_dir_index| save_dir_index.find_encoded(video_path)).transpose()?.flatten() {
+        report!("Skipping video {} as it is already encoded: {}", video_path.display(), encoded_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    if let Some(history_db) = HISTORY_DB.get().filter(|_| config.skip_encoded_by_history && !ctx.opts.force_reencode) {
+        if let Some(output_path) = history_db.find_output(video_path)? {
+            report!("Skipping video {} as history has its output: {}", video_path.display(), output_path.display());
+            return Ok(FileOutcome::Skipped);
+        }
+    }
+
+    if let Some(catalog_match) = ctx.catalog.as_ref().map(|catalog| catalog.find(video_path, config)).transpose()?.flatten() {
+        report!("Skipping video {} as it is in the catalog: {}", video_path.display(), catalog_match);
+        return Ok(FileOutcome::Skipped);
+    }
+
+    let probe = probe_video(video_path)?;
+    log::debug!("Probe: {:?}", probe);
+
+    if let (Some(min_bit_rate), Some(bit_rate)) = (config.skip_if_source_bitrate_below, probe.bit_rate) {
+        if bit_rate < min_bit_rate {
+            report!("Skipping video {} as its bitrate is already low ({} < {} bps)", video_path.display(), bit_rate, min_bit_rate);
+            return Ok(FileOutcome::Skipped);
+        }
+    }
+
+    if !config.allowed_aspect_ratios.is_empty() {
+        let aspect_ratio = probe.display_aspect_ratio();
+        let allowed = config.allowed_aspect_ratios.iter().filter_map(|allowed| parse_aspect_ratio(allowed))
+            .any(|allowed| (aspect_ratio - allowed).abs() <= allowed * config.aspect_ratio_tolerance);
+        if !allowed {
+            report!("Skipping video {} as its aspect ratio {:.3} is not one of {:?}", video_path.display(), aspect_ratio, config.allowed_aspect_ratios);
+            flag_for_review(video_path, ReviewReason::AspectRatio, config);
+            return Ok(FileOutcome::Skipped);
+        }
+    }
+
+    // decided only once the video is known to be encoded, as the grain probe decodes a sample
+    let undenoised_config;
+    let config = match &config.denoise {
+        Some(denoise) if !should_denoise(video_path, sidecar.denoise, denoise, probe.duration_secs)? => {
+            undenoised_config = Config { denoise: None, ..config.clone() };
+            &undenoised_config
+        },
+        _ => config,
+    };
+
+    // the sidecar is more specific than the central policy, so it wins
+    let policy = match &config.target_policy_hook {
+        Some(hook) if sidecar.crf.is_none() && sidecar.target_vmaf.is_none() => Some(exec_target_policy_hook(video_path, &probe, hook)?),
+        _ => None,
+    };
+    let crf = match policy {
+        Some(TargetPolicy::Crf(crf)) => Some(crf),
+        _ => sidecar.crf,
+    };
+
+    // resolved only once a target is known to be encoded, as the complexity probe decodes a sample
+    let mut resolved_target_vmaf = None;
+    let mut base_target_vmaf = || -> Result<u8> {
+        if let Some(resolved_target_vmaf) = resolved_target_vmaf {
+            return Ok(resolved_target_vmaf);
+        }
+        let base_target_vmaf = match (policy, config.complexity_vmaf_band, sidecar.target_vmaf) {
+            (Some(TargetPolicy::Vmaf(policy_target_vmaf)), _, _) => policy_target_vmaf,
+            (None, Some(complexity_vmaf_band), None) => complexity_target_vmaf(video_path, probe.duration_secs, complexity_vmaf_band)?,
+            _ => target_vmaf,
+        };
+        resolved_target_vmaf = Some(base_target_vmaf);
+        Ok(base_target_vmaf)
+    };
+
+    // only a search that reached the current target counts, renditions each have their own crf
+    let mut crf = crf;
+    if let Some(history_db) = HISTORY_DB.get().filter(|_| crf.is_none() && config.reuse_recorded_crf && config.renditions.is_empty()) {
+        if let Some((recorded_crf, recorded_vmaf)) = history_db.find_crf(video_path, &config.encoder)? {
+            if recorded_vmaf >= base_target_vmaf()? as f64 {
+                report!("Reusing crf {} recorded with VMAF {:.2}: {}", recorded_crf, recorded_vmaf, video_path.display());
+                crf = Some(recorded_crf.round() as u8);
+            }
+        }
+    }
+
+    let targets = if config.renditions.is_empty() {
+        if save_path.exists() && !ctx.opts.force_reencode {
+            if delete_almost_same_files {
+                if !is_valid_video_file(encoded_output_probe_path(&save_path), config)? {
+                    return Err(anyhow!(Error::FoundInvalidVideoFileInSavedPath(save_path.clone())));
+                }
+
+                let duration_of_saved_video = rough_video_secs(encoded_output_probe_path(&save_path), config)?;
+                let duration_of_current_video = rough_video_secs(video_path, config)?;
+
+                match (duration_of_saved_video, duration_of_current_video) {
+                    (Some(duration_of_saved_video), Some(duration_of_current_video)) if jdt::almost_eq(duration_of_saved_video, duration_of_current_video, 0.01) => {
+                        report!("Removing a file having duplicate name, almost equal duration video: {}", video_path.display());
+                        fs::remove_file(video_path)?;
+                        return Ok(FileOutcome::Removed);
+                    },
+                    (Some(duration_of_saved_video), Some(duration_of_current_video)) => {
+                        report!("Skipping video for now, duplicated names, but different durations ({} != {}): {}", duration_of_saved_video, duration_of_current_video, save_path.display());
+                    },
+                    _ => {
+                        report!("Skipping video for now, duplicated names, but unknown duration: {}", save_path.display());
+                    },
+                }
+            } else {
+                report!("Skipping video {} as it already exists in save directory", video_path.display());
+            }
+            return Ok(FileOutcome::Skipped);
+        }
+        vec![EncodeTarget {
+            name: None,
+            save_path,
+            encoding_video_path,
+            target_vmaf: base_target_vmaf()?,
+            crf,
+            scale_height: downscale_height(probe.filter_height(config), config.max_height),
+        }]
+    } else {
+        let mut targets = Vec::new();
+        for rendition in &config.renditions {
+            let rendition_save_path = rendition_file_save_path(&save_path, &rendition.name);
+            if rendition_save_path.exists() && !ctx.opts.force_reencode {
+                report!("Skipping rendition {} of video {} as it already exists in save directory", rendition.name, video_path.display());
+                continue;
+            }
+            targets.push(EncodeTarget {
+                name: Some(rendition.name.clone()),
+                save_path: rendition_save_path,
+                encoding_video_path: suffixed_encoding_video_path(&encodnig_video_dir, &video_location_hash, &rendition.name, config),
+                target_vmaf: match rendition.target_vmaf {
+                    Some(target_vmaf) => target_vmaf,
+                    None => base_target_vmaf()?,
+                },
+                crf,
+                scale_height: downscale_height(probe.filter_height(config), [rendition.height, config.max_height].into_iter().flatten().min()),
+            });
+        }
+        if targets.is_empty() {
+            report!("Skipping video {} as all renditions already exist in save directory", video_path.display());
+            return Ok(FileOutcome::Skipped);
+        }
+        targets
+    };
+
+    if config.hls.is_some() && targets.iter().any(|target| target.crf.is_none()) {
+        report!("Skipping video {} as HLS output needs crf, e.g. from its sidecar", video_path.display());
+        return Ok(FileOutcome::Skipped);
+    }
+
+    // a full decode is slow, so only once we know there's something to encode
+    if let Some(max_source_decode_errors) = config.max_source_decode_errors {
+        let decode_errors = count_decode_errors(video_path)?;
+        if decode_errors > max_source_decode_errors {
+            report!("Skipping video {} as it has too many decode errors ({} > {})", video_path.display(), decode_errors, max_source_decode_errors);
+            flag_for_review(video_path, ReviewReason::DecodeErrors, config);
+            return Ok(FileOutcome::Skipped);
+        }
+    }
+
+    if move_failed_files && failed_copy_path.exists() {
+        return Err(anyhow!(Error::ConflictFailedCopyPath(video_path.to_path_buf(), failed_copy_path)));
+    }
+
+    for target in &targets {
+        if target.encoding_video_path.exists() {
+            return Err(anyhow!(Error::ConflictVideoEncoding(video_path.to_path_buf(), target.encoding_video_path.to_path_buf())));
+        }
+    }
+
+    let max_encoded_percent = max_encoded_percent_for_height(probe.height, config);
+
+    let mut all_saved = true;
+    let mut failed = false;
+    let mut salvaged = false;
+    let mut incompressible = false;
+    let mut encoded_paths = Vec::new();
+    for target in targets {
+        match &target.name {
+            Some(name) => report!("Encoding video (rendition {}): {}", name, video_path.display()),
+            None => report!("Encoding video: {}", video_path.display()),
+        }
+        let encoding_video_path = &target.encoding_video_path;
+        let start_encoding = Instant::now();
+        let result = if let Some(crf) = target.crf {
+            exec_force_crf_ffmpeg(video_path, encoding_video_path, crf, target.scale_height, None, config).map(|_| AbAv1Stats { crf: Some(crf as f32), vmaf: None })
+        } else {
+            match exec_ab_av1(video_path, encoding_video_path, target.target_vmaf, target.scale_height, max_encoded_percent, false, &ctx.inherited_log_level, config) {
+                Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::VmafTargetUnreachable(_))) && config.min_acceptable_vmaf.is_some_and(|min_vmaf| min_vmaf < target.target_vmaf) => {
+                    let min_vmaf = config.min_acceptable_vmaf.expect("min acceptable vmaf must be checked above");
+                    report!("Target VMAF {} unreachable, retrying with minimum acceptable VMAF {}: {}", target.target_vmaf, min_vmaf, video_path.display());
+                    if encoding_video_path.exists() {
+                        remove_encoded_output(encoding_video_path)?;
+                    }
+                    let result = exec_ab_av1(video_path, encoding_video_path, min_vmaf, target.scale_height, max_encoded_percent, false, &ctx.inherited_log_level, config);
+                    salvaged |= result.is_ok();
+                    result
+                },
+                Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::MaxEncodedPercentExceeded(_))) && config.on_max_encoded_percent_exceeded.retry_percent().is_some_and(|retry_percent| Some(retry_percent) > max_encoded_percent) => {
+                    let retry_percent = config.on_max_encoded_percent_exceeded.retry_percent().expect("retry percent must be checked above");
+                    report!("No crf fits under {}% of the source, retrying with {}%: {}", max_encoded_percent.unwrap_or_default(), retry_percent, video_path.display());
+                    if encoding_video_path.exists() {
+                        remove_encoded_output(encoding_video_path)?;
+                    }
+                    exec_ab_av1(video_path, encoding_video_path, target.target_vmaf, target.scale_height, Some(retry_percent), false, &ctx.inherited_log_level, config)
+                },
+                result => result,
+            }
+        };
+        let mut exceeded_max_encoded_percent = false;
+        let stats = match result {
+            Ok(stats) => {
+                if let Some(vmaf) = stats.vmaf {
+                    report!("Achieved VMAF {:.2}{}", vmaf, stats.crf.map(|crf| format!(" with crf {}", crf)).unwrap_or_default());
+                }
+                if config.log_encode_speed {
+                    log_encode_speed(video_path, &probe, start_encoding.elapsed());
+                }
+                Some(stats)
+            },
+            Err(e) => {
+                match e.downcast_ref::<Error>() {
+                    Some(Error::VmafTargetUnreachable(_)) => {
+                        flag_for_review(video_path, ReviewReason::VmafUnreachable, config);
+                        None
+                    },
+                    Some(Error::AbAv1CommandFailed(_) | Error::ForceCrfFfmpegCommandFailed(_) | Error::CrfSearchWithoutCrf(_)) => None,
+                    // the source just doesn't benefit, which is no reason to call it failed
+                    Some(Error::MaxEncodedPercentExceeded(_)) => {
+                        report!("No crf fits under max_encoded_percent, keeping original video: {}", video_path.display());
+                        exceeded_max_encoded_percent = true;
+                        None
+                    },
+                    Some(Error::MaxRuntimeExceeded | Error::Interrupted) => {
+                        if encoding_video_path.exists() {
+                            remove_encoded_output(encoding_video_path)?;
+                        }
+                        return Err(e);
+                    },
+                    _ => return Err(e),
+                }
+            }
+        };
+
+        // a signal may land right after the encoder exits, the output must not be promoted then
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            if encoding_video_path.exists() {
+                remove_encoded_output(encoding_video_path)?;
+            }
+            return Err(anyhow!(Error::Interrupted));
+        }
+
+        if let Some(stats) = stats {
+            if encoding_video_path.is_dir() {
+                if let Err(e) = verify_hls_playlist(encoding_video_path) {
+                    log::warn!("Encoding failed for {:?}: {}", video_path, e);
+                    remove_encoded_output(encoding_video_path)?;
+                    all_saved = false;
+                    failed = true;
+                    continue;
+                }
+            }
+
+            if encoding_video_path.exists() && !is_valid_video_file(encoded_output_probe_path(encoding_video_path), config)? {
+                log::warn!("Encoding failed for {:?}: Invalid video file", video_path);
+                remove_encoded_output(encoding_video_path)?;
+                all_saved = false;
+                continue;
+            }
+
+            if let Err(e) = check_output_duration(encoded_output_probe_path(encoding_video_path), config) {
+                if !matches!(e.downcast_ref::<Error>(), Some(Error::ZeroDurationOutput(_))) {
+                    return Err(e);
+                }
+                log::warn!("Encoding failed for {:?}: {}", video_path, e);
+                report!("Encoding failed with zero duration output: {}", video_path.display());
+                remove_encoded_output(encoding_video_path)?;
+                all_saved = false;
+                failed = true;
+                continue;
+            }
+
+            if let Err(e) = check_output_frame_rate(encoded_output_probe_path(encoding_video_path), video_path, config) {
+                if !matches!(e.downcast_ref::<Error>(), Some(Error::FrameRateMismatch(..))) {
+                    return Err(e);
+                }
+                log::warn!("Encoding failed for {:?}: {}", video_path, e);
+                report!("Encoding failed with a different frame rate: {}", video_path.display());
+                remove_encoded_output(encoding_video_path)?;
+                all_saved = false;
+                failed = true;
+                continue;
+            }
+
+            if let Err(e) = check_output_size(encoding_video_path, video_path, config) {
+                if !matches!(e.downcast_ref::<Error>(), Some(Error::TinyOutput(..))) {
+                    return Err(e);
+                }
+                log::warn!("Encoding failed for {:?}: {}", video_path, e);
+                report!("Encoding failed with implausibly small output: {}", video_path.display());
+                remove_encoded_output(encoding_video_path)?;
+                all_saved = false;
+                failed = true;
+                continue;
+            }
+
+            encoded_paths.push((target.encoding_video_path.clone(), target.save_path, stats));
+        } else {
+            if encoding_video_path.exists() {
+                remove_encoded_output(encoding_video_path)?;
+            }
+            all_saved = false;
+            if exceeded_max_encoded_percent {
+                incompressible = true;
+            } else {
+                failed = true;
+            }
+        }
+    }
+
+    let outcome = if failed {
+        FileOutcome::Failed
+    } else if incompressible {
+        FileOutcome::Incompressible
+    } else if all_saved && salvaged {
+        FileOutcome::Salvaged
+    } else if all_saved {
+        FileOutcome::Encoded
+    } else {
+        FileOutcome::Skipped
+    };
+    let job = SaveJob {
+        video_path: video_path.to_path_buf(),
+        config: config.clone(),
+        encoded_paths,
+        duration_secs: probe.duration_secs,
+        remove_original: all_saved && !config.keep_original,
+        failed_copy_path: (failed && move_failed_files).then_some(failed_copy_path),
+        outcome,
+        file_label: file_label(),
+    };
+
+    match &ctx.save_sender {
+        Some(save_sender) => {
+            save_sender.send(job).expect("save queue must outlive encode workers");
+            Ok(FileOutcome::Queued)
+        },
+        None => save_encoded_video(job),
+    }
+}
+
+fn save_encoded_video(job: SaveJob) -> Result<FileOutcome> {
+    let video_path = &job.video_path;
+    let config = &job.config;
+
+    // taken before anything is saved, as the original may be removed right after
+    let source_fingerprint = if config.skip_encoded_by_fingerprint && !job.encoded_paths.is_empty() { Some(content_fingerprint(video_path)?) } else { None };
+
+    let source_size = fs::metadata(video_path)?.len();
+    let mut larger_output = false;
+    let mut saved_paths = Vec::new();
+    for (encoding_video_path, save_path, stats) in job.encoded_paths {
+        // checked before saving, so a bloated output never gets copied to a slow save_dir
+        if config.on_larger_output != LargerOutputAction::Keep {
+            let output_size = encoded_output_size(&encoding_video_path)?;
+            if output_size > source_size {
+                log::info!("Output {:?} ({} bytes) is larger than source {:?} ({} bytes), {:?}", encoding_video_path, output_size, video_path, source_size, config.on_larger_output);
+                report!("Discarding output larger than source ({} > {} bytes): {}", output_size, source_size, video_path.display());
+                remove_encoded_output(&encoding_video_path)?;
+                larger_output = true;
+                continue;
+            }
+        }
+
+        // measured on the local encode, as save_dir may be slow to read back
+        let quality_scores = if config.quality_metrics_to_record.is_empty() {
+            Vec::new()
+        } else {
+            match measure_quality_metrics(encoded_output_probe_path(&encoding_video_path), video_path, None, &config.quality_metrics_to_record) {
+                Ok(quality_scores) => quality_scores,
+                Err(e) => {
+                    log::warn!("Failed to measure quality of {:?}: {:?}", encoding_video_path, e);
+                    Vec::new()
+                },
+            }
+        };
+
+        let start_saving = std::time::Instant::now();
+        report!("Saving video to: {}", save_path.display());
+        if save_path.exists() {
+            replace_in_save_dir(&encoding_video_path, &save_path, config)?;
+        } else {
+            rename_into_save_dir(&encoding_video_path, &save_path, config)?;
+        }
+        if config.preserve_xattrs {
+            copy_xattrs(video_path, &save_path);
+        }
+        if let Some(source_fingerprint) = &source_fingerprint {
+            if let Err(e) = xattr::set(&save_path, SOURCE_FINGERPRINT_XATTR, source_fingerprint.as_bytes()) {
+                log::warn!("Failed to tag {:?} with its source fingerprint: {:?}", save_path, e);
+            }
+        }
+        for (metric, score) in config.quality_metrics_to_record.iter().zip(&quality_scores) {
+            report!("{} {:.4}: {}", metric.name().to_uppercase(), score, save_path.display());
+            if let Err(e) = xattr::set(&save_path, format!("user.batchav1.{}", metric.name()), score.to_string().as_bytes()) {
+                log::warn!("Failed to record {} of {:?}: {:?}", metric.name(), save_path, e);
+            }
+        }
+        let elapsed = start_saving.elapsed();
+        if elapsed.as_secs() > 10 {
+            report!("Saved in {:.2} sec", elapsed.as_secs_f64());
+        }
+        // the output is already saved, so a broken preview only costs the preview
+        if let Some(preview) = &config.preview {
+            match exec_preview_ffmpeg(&save_path, preview, config) {
+                Ok(preview_path) => report!("Saved preview to: {}", preview_path.display()),
+                Err(e) => {
+                    log::warn!("Failed to generate preview for {:?}: {:?}", save_path, e);
+                    report!("Failed to generate preview for {}: {}", save_path.display(), e);
+                },
+            }
+        }
+        if let Some(reference_size) = reference_size(video_path) {
+            if let Ok(output_size) = encoded_output_size(&save_path) {
+                report!("Output is {:.1}% of the original ({} of {} bytes): {}", output_size as f64 * 100.0 / reference_size as f64, output_size, reference_size, save_path.display());
+            }
+        }
+        if let Some(history_db) = HISTORY_DB.get() {
+            if let Err(e) = history_db.record_output(video_path, &save_path, source_size, stats, job.duration_secs, config) {
+                log::warn!("Failed to record history of {:?}: {:?}", save_path, e);
+            }
+        }
+        saved_paths.push(save_path);
+    }
+
+    if larger_output {
+        if config.on_larger_output == LargerOutputAction::KeepOriginalMoveSource {
+            let save_dir = encoded_file_save_dir(video_path, config);
+            let source_save_path = save_dir.join(rename_for_linux_limit::new_filename(video_path, Some(&save_dir))?);
+            if source_save_path.exists() {
+                report!("Keeping source in place, as the save path already exists: {}", source_save_path.display());
+            } else {
+                report!("Moving source to: {}", source_save_path.display());
+                rename_into_save_dir(video_path, &source_save_path, config)?;
+            }
+        } else {
+            report!("Keeping original video: {}", video_path.display());
+        }
+        remove_merged_multipart(video_path, config)?;
+        return Ok(FileOutcome::Skipped);
+    }
+
+    if job.remove_original {
+        remove_original_video(video_path, &saved_paths, config)?;
+        // the merged source is only gone once the outputs checked out, and then the parts can go too
+        if !video_path.exists() {
+            for part_path in &config.multipart_sources {
+                report!("Removing part: {}", part_path.display());
+                fs::remove_file(part_path)?;
+            }
+        }
+    }
+
+    // only a kept source has anywhere to carry the marker
+    if let Some(done_marker) = config.done_marker.as_ref().filter(|_| config.write_done_marker) {
+        if matches!(job.outcome, FileOutcome::Encoded | FileOutcome::Salvaged) && video_path.exists() {
+            if let Err(e) = write_done_marker(video_path, done_marker) {
+                log::warn!("Failed to write done marker for {:?}: {:?}", video_path, e);
+            }
+        }
+    }
+
+    if let Some(failed_copy_path) = &job.failed_copy_path {
+        report!("Moving failed video ...");
+        rename_into_save_dir(video_path, failed_copy_path, config)?;
+    }
+
+    if let (FileOutcome::Incompressible, MaxEncodedPercentAction::MoveTo(incompressible_dir)) = (job.outcome, &config.on_max_encoded_percent_exceeded) {
+        create_save_dir(incompressible_dir, config)?;
+        let incompressible_path = incompressible_dir.join(rename_for_linux_limit::new_filename(video_path, Some(incompressible_dir))?);
+        if incompressible_path.exists() {
+            report!("Keeping source in place, as the incompressible path already exists: {}", incompressible_path.display());
+        } else {
+            report!("Moving incompressible video to: {}", incompressible_path.display());
+            rename_into_save_dir(video_path, &incompressible_path, config)?;
+        }
+    }
+
+    remove_merged_multipart(video_path, config)?;
+    Ok(job.outcome)
+}
+
+// "user.*" markers are extended attributes on the source, anything else a file next to it,
+// e.g. "{name}.done" for "movie.mkv.done", or ".done" marking the whole directory
+fn done_marker_path(video_path: impl AsRef<Path>, done_marker: &str) -> Option<PathBuf> {
+    let video_path = video_path.as_ref();
+    if done_marker.starts_with("user.") {
+        return None;
+    }
+    let filename = video_path.file_name().unwrap_or_default().to_string_lossy();
+    Some(video_path.with_file_name(done_marker.replace("{name}", &filename)))
+}
+
+fn has_done_marker(video_path: impl AsRef<Path>, done_marker: &str) -> bool {
+    let video_path = video_path.as_ref();
+    match done_marker_path(video_path, done_marker) {
+        Some(done_marker_path) => done_marker_path.exists(),
+        None => matches!(xattr::get(video_path, done_marker), Ok(Some(_))),
+    }
+}
+
+fn write_done_marker(video_path: impl AsRef<Path>, done_marker: &str) -> Result<()> {
+    let video_path = video_path.as_ref();
+    match done_marker_path(video_path, done_marker) {
+        Some(done_marker_path) => {
+            fs::write(done_marker_path, "")?;
+        },
+        None => xattr::set(video_path, done_marker, b"1")?,
+    }
+    Ok(())
+}
+
+fn run_debug_single_command(opts: DebugSingleOpts, mut config: Config) -> Result<()> {
+    // ab-av1 would silently encode the default streams
+    if (opts.video_stream.is_some() || opts.audio_stream.is_some()) && !config.use_ab_av1_crf_with_ffmpeg {
+        return Err(anyhow!(Error::StreamSelectionWithoutFfmpeg));
+    }
+    config.video_stream = opts.video_stream.or(config.video_stream);
+    config.audio_stream = opts.audio_stream.or(config.audio_stream);
+    config.output_extension = config.output_extension_for(&opts.video_path);
+    // named after the source, so that debugging several videos doesn't overwrite one output
+    let output_path = match &opts.output {
+        Some(output) => output.clone(),
+        None => {
+            let video_stem = opts.video_path.file_stem().ok_or(Error::InvalidVideoPath(opts.video_path.clone()))?;
+            let mut output_filename = video_stem.to_os_string();
+            output_filename.push(format!(".debug.{}", config.output_extension));
+            config.save_dir.join(output_filename)
+        },
+    };
+
+    log::debug!("Running debug single command with opts: {:?}", opts);
+    log::debug!("Output path: {:?}", output_path);
+    check_save_dir_writable(&output_path, &config)?;
+
+    let probe = probe_video(&opts.video_path)?;
+    log::debug!("Probe: {:?}", probe);
+
+    let log_path = opts.log_file.as_ref().map(|log_file| log_file.clone().unwrap_or_else(|| {
+        let mut log_path = output_path.clone().into_os_string();
+        log_path.push(".log");
+        PathBuf::from(log_path)
+    }));
+    if let Some(log_path) = &log_path {
+        // exec_ab_av1 appends, so a log of an earlier run must not stay in front
+        match fs::remove_file(log_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {},
+        }
+        AB_AV1_LOG.with(|current| *current.borrow_mut() = Some(log_path.clone()));
+    }
+
+    let result = exec_ab_av1(&opts.video_path, &output_path, opts.target_vmaf, downscale_height(probe.filter_height(&config), config.max_height), max_encoded_percent_for_height(probe.height, &config), true, "debug", &config);
+    log::debug!("Result: {:?}", result);
+
+    // printed whether or not the search found a crf, a failed search is what's most worth looking at
+    if let Some(log_path) = &log_path {
+        let lines: Vec<String> = BufReader::new(fs::File::open(log_path)?).lines().collect::<std::io::Result<_>>()?;
+        println!("Saved ab-av1 output to: {}", log_path.display());
+        println!("Crf search trace:");
+        for attempt in parse_crf_search_trace(&lines) {
+            match attempt.encoded_percent {
+                Some(encoded_percent) => println!("  crf {} VMAF {:.2} ({:.0}%)", attempt.crf, attempt.vmaf, encoded_percent),
+                None => println!("  crf {} VMAF {:.2}", attempt.crf, attempt.vmaf),
+            }
+        }
+        match &result {
+            Ok(AbAv1Stats { crf: Some(crf), vmaf }) => println!("Chose crf {} with VMAF {}", crf, vmaf.map_or("unknown".to_string(), |vmaf| format!("{:.2}", vmaf))),
+            Ok(_) => println!("Chose a crf, but ab-av1 didn't tell which"),
+            Err(e) => println!("No crf chosen: {}", e),
+        }
+    }
+
+    result?;
+    Ok(())
+}
+
+fn run_force_crf_single_command(opts: ForceCrfSingleOpts, mut config: Config) -> Result<()> {
+    config.output_extension = config.output_extension_for(&opts.video_path);
+    config.video_stream = opts.video_stream.or(config.video_stream);
+    config.audio_stream = opts.audio_stream.or(config.audio_stream);
+    let encodnig_video_dir = config.encoding_video_dir_for(encoded_file_save_dir(&opts.video_path, &config));
+    let video_path = &opts.video_path;
+    create_save_dir(encoded_file_save_dir(video_path, &config), &config)?;
+    fs::create_dir_all(&encodnig_video_dir)?;
+
+    let video_location_hash = hash_file_location(&video_path);
+    let encoding_video_path = encodnig_video_dir.join(&video_location_hash).with_extension(&config.output_extension);
+    let save_path = match &opts.output {
+        Some(output) => {
+            if let Some(output_dir) = output.parent().filter(|output_dir| !output_dir.as_os_str().is_empty()) {
+                create_save_dir(output_dir, &config)?;
+            }
+            output.clone()
+        },
+        None => encoded_file_save_path(&opts.video_path, &config)?,
+    };
+
+    // each chapter becomes its own file with split_by_chapters, otherwise the whole video is one segment
+    let chapters = if config.split_by_chapters { video_chapters(video_path)? } else { Vec::new() };
+    let segments = if chapters.len() > 1 {
+        chapter_segments(&chapters, &save_path, &encodnig_video_dir, &video_location_hash, &config)
+    } else {
+        vec![(save_path, encoding_video_path, None)]
+    };
+
+    for (save_path, encoding_video_path, _) in &segments {
+        if save_path.exists() {
+            return Err(anyhow!(Error::SingleEncodeSavePathAlreadyExists(save_path.clone())));
+        }
+
+        if encoding_video_path.exists() {
+            return Err(anyhow!(Error::ConflictVideoEncoding(video_path.clone(), encoding_video_path.clone())));
+        }
+    }
+
+    let probe = probe_video(&video_path)?;
+    log::debug!("Probe: {:?}", probe);
+
+    for (_, encoding_video_path, time_range) in &segments {
+        match time_range {
+            Some((start_secs, end_secs)) => println!("Encoding video ({:.2} - {:.2} sec): {}", start_secs, end_secs, video_path.display()),
+            None => println!("Encoding video: {}", video_path.display()),
+        }
+        exec_force_crf_ffmpeg(&opts.video_path, encoding_video_path, opts.crf, downscale_height(probe.filter_height(&config), config.max_height), *time_range, &config)?;
+
+        if encoding_video_path.exists() && !is_valid_video_file(encoding_video_path, &config)? {
+            fs::remove_file(encoding_video_path)?;
+            return Err(anyhow!(Error::SingleEncodeFailedWithInvalidEncodedFile(video_path.clone(), encoding_video_path.clone())));
+        }
+    }
+
+    if segments.len() > 1 {
+        let mut total_secs = 0.0;
+        for (_, encoding_video_path, _) in &segments {
+            total_secs += rough_video_secs(encoding_video_path, &config)?.unwrap_or_default();
+        }
+        let source_secs = probe.duration_secs.unwrap_or_default();
+        // each cut can be off by up to a GOP, so allow a second per chapter
+        if (total_secs - source_secs).abs() > segments.len() as f64 {
+            for (_, encoding_video_path, _) in &segments {
+                fs::remove_file(encoding_video_path)?;
+            }
+            return Err(anyhow!(Error::ChapterDurationMismatch(video_path.clone(), total_secs, source_secs)));
+        }
+    }
+
+    let mut saved_paths = Vec::new();
+    for (save_path, encoding_video_path, _) in segments {
+        let start_saving = std::time::Instant::now();
+        println!("Saving video to: {}", save_path.display());
+        rename_into_save_dir(&encoding_video_path, &save_path, &config)?;
+        if config.preserve_xattrs {
+            copy_xattrs(video_path, &save_path);
+        }
+        let elapsed = start_saving.elapsed();
+        if elapsed.as_secs() > 10 {
+            println!("Saved in {:.2} sec", elapsed.as_secs_f64());
+        }
+        saved_paths.push(save_path);
+    }
+
+    if !config.keep_original {
+        remove_original_video(video_path, &saved_paths, &config)?;
+    }
+
+    Ok(())
+}
+
+// every write into save directories goes through these, so readonly_save can reject them
+fn check_save_dir_writable(path: impl AsRef<Path>, config: &Config) -> Result<()> {
+    let path = path.as_ref();
+    if config.readonly_save {
+        return Err(anyhow!(Error::ReadonlySaveDir(path.to_path_buf())));
+    }
+    Ok(())
+}
+
+fn create_save_dir(save_dir: impl AsRef<Path>, config: &Config) -> Result<()> {
+    let save_dir = save_dir.as_ref();
+    if !save_dir.is_dir() {
+        check_save_dir_writable(save_dir, config)?;
+        match fs::create_dir_all(save_dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return Err(anyhow!(Error::SaveDirNotWritable(save_dir.to_path_buf()))),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+fn rename_into_save_dir(from_path: impl AsRef<Path>, save_path: impl AsRef<Path>, config: &Config) -> Result<()> {
+    let save_path = save_path.as_ref();
+    check_save_dir_writable(save_path, config)?;
+    if from_path.as_ref().is_dir() {
+        rename_dir(from_path, save_path)?;
+    } else if config.verify_save_checksum && !is_same_device(&from_path, save_path)? {
+        copy_verified(from_path, save_path)?;
+    } else {
+        jdt::rename_file(from_path, save_path)?;
+    }
+    Ok(())
+}
+
+// For --force-reencode, the new output is saved next to the old one first,
+// so a failed save never leaves the existing output half overwritten
+fn replace_in_save_dir(from_path: impl AsRef<Path>, save_path: impl AsRef<Path>, config: &Config) -> Result<()> {
+    let save_path = save_path.as_ref();
+    // before the leftover cleanup, which writes too
+    check_save_dir_writable(save_path, config)?;
+    let filename = save_path.file_name().ok_or(Error::InvalidVideoPath(save_path.to_path_buf()))?.to_string_lossy();
+    let replacing_path = save_path.with_file_name(format!(".{}.replacing", filename));
+    if replacing_path.exists() {
+        log::warn!("Removing leftover of an interrupted replace: {:?}", replacing_path);
+        remove_encoded_output(&replacing_path)?;
+    }
+    rename_into_save_dir(from_path, &replacing_path, config)?;
+
+    report!("Replacing existing output: {}", save_path.display());
+    if replacing_path.is_dir() {
+        // a directory can't be renamed over a non-empty one, so the old output steps aside for a moment
+        let replaced_path = save_path.with_file_name(format!(".{}.replaced", filename));
+        fs::rename(save_path, &replaced_path)?;
+        fs::rename(&replacing_path, save_path)?;
+        fs::remove_dir_all(&replaced_path)?;
+    } else {
+        fs::rename(&replacing_path, save_path)?;
+    }
+    Ok(())
+}
+
+// HLS outputs are flat directories of segments, copied file by file across devices
+fn rename_dir(from_dir: impl AsRef<Path>, save_dir: impl AsRef<Path>) -> Result<()> {
+    let from_dir = from_dir.as_ref();
+    let save_dir = save_dir.as_ref();
+    match fs::rename(from_dir, save_dir) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            fs::create_dir_all(save_dir)?;
+            for entry in fs::read_dir(from_dir)? {
+                let entry = entry?;
+                fs::copy(entry.path(), save_dir.join(entry.file_name()))?;
+            }
+            fs::remove_dir_all(from_dir)?;
+            Ok(())
+        },
+        result => Ok(result?),
+    }
+}
+
+fn is_same_device(from_path: impl AsRef<Path>, save_path: impl AsRef<Path>) -> Result<bool> {
+    let from_path = from_path.as_ref();
+    let save_path = save_path.as_ref();
+    let save_dir = save_path.parent().unwrap_or(save_path);
+    Ok(fs::metadata(from_path)?.dev() == fs::metadata(save_dir)?.dev())
+}
+
+const SAVE_COPY_ATTEMPTS: usize = 2;
+
+// Cross-device saves are copies, which a flaky mount can silently corrupt,
+// so the source is kept until the copy hashes the same
+fn copy_verified(from_path: impl AsRef<Path>, save_path: impl AsRef<Path>) -> Result<()> {
+    let from_path = from_path.as_ref();
+    let save_path = save_path.as_ref();
+    let source_hash = file_hash(from_path)?;
+    for attempt in 1..=SAVE_COPY_ATTEMPTS {
+        fs::copy(from_path, save_path)?;
+        let saved_hash = file_hash(save_path)?;
+        if saved_hash == source_hash {
+            fs::remove_file(from_path)?;
+            return Ok(());
+        }
+        log::warn!("Checksum mismatch after copying {:?} to {:?} (attempt {}/{})", from_path, save_path, attempt, SAVE_COPY_ATTEMPTS);
+        fs::remove_file(save_path)?;
+    }
+    Err(anyhow!(Error::SaveChecksumMismatch(from_path.to_path_buf(), save_path.to_path_buf())))
+}
+
+// Finder tags, comments, etc. failures only warn, as the encoded video itself is fine
+fn copy_xattrs(from_path: impl AsRef<Path>, to_path: impl AsRef<Path>) {
+    let from_path = from_path.as_ref();
+    let to_path = to_path.as_ref();
+    if !xattr::SUPPORTED_PLATFORM {
+        log::warn!("Extended attributes are not supported on this platform, not preserving them");
+        return;
+    }
+    let names = match xattr::list(from_path) {
+        Ok(names) => names,
+        Err(e) => {
+            log::warn!("Failed to list extended attributes of {:?}: {:?}", from_path, e);
+            return;
+        },
+    };
+    for name in names {
+        match xattr::get(from_path, &name) {
+            Ok(Some(value)) => {
+                if let Err(e) = xattr::set(to_path, &name, &value) {
+                    log::warn!("Failed to set extended attribute {:?} on {:?}: {:?}", name, to_path, e);
+                }
+            },
+            Ok(None) => {},
+            Err(e) => log::warn!("Failed to get extended attribute {:?} of {:?}: {:?}", name, from_path, e),
+        }
+    }
+}
+
+fn remove_original_video(video_path: impl AsRef<Path>, saved_paths: &[PathBuf], config: &Config) -> Result<()> {
+    let video_path = video_path.as_ref();
+    if config.verify_before_delete {
+        for saved_path in saved_paths {
+            report!("Verifying saved video: {}", saved_path.display());
+            if !is_decodable_video_file(encoded_output_probe_path(saved_path))? {
+                log::warn!("Saved video {:?} doesn't decode cleanly, keeping original {:?}", saved_path, video_path);
+                report!("Keeping original video as saved video doesn't decode cleanly: {}", saved_path.display());
+                return Ok(());
+            }
+        }
+    }
+    report!("Removing original video ...");
+    fs::remove_file(video_path)?;
+    log::debug!("Removed original video {:?}", video_path);
+    Ok(())
+}
+
+// prints every decision input of run_all per file, without touching anything
+fn run_diagnose_command(opts: DiagnoseOpts, config: Config) -> Result<()> {
+    for video_path in jdt::walk_dir(&opts.video_dir, |path| path) {
+        println!("{}", video_path.display());
+        if is_junk(&video_path) {
+            println!("  junk: yes (would be removed)");
+            continue;
+        }
+
+        let guessed_video = guess_video_file(&video_path);
+        println!("  guessed video: {}", if guessed_video { "yes" } else { "no" });
+        if !guessed_video {
+            continue;
+        }
+
+        let valid = is_valid_video_file(&video_path, &config)?;
+        println!("  valid: {}", if valid { "yes" } else { "no" });
+        if !valid {
+            continue;
+        }
+
+        match probe_video(&video_path) {
+            Ok(probe) => {
+                println!("  duration: {}", probe.duration_secs.map(|secs| format!("{:.2} sec", secs)).unwrap_or("unknown".to_string()));
+                println!("  dimensions: {}x{}", probe.width, probe.height);
+                println!("  codec: {}", probe.codec_name.as_deref().unwrap_or("unknown"));
+                println!("  bitrate: {}", probe.bit_rate.map(|bit_rate| format!("{} bps", bit_rate)).unwrap_or("unknown".to_string()));
+            },
+            Err(e) => println!("  probe failed: {}", e),
+        }
+
+        match encoded_file_save_path(&video_path, &config) {
+            Ok(save_path) => println!("  save path: {}{}", save_path.display(), if save_path.exists() { " (exists)" } else { "" }),
+            Err(e) => println!("  save path failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+// [[profiles]]
+// name = "anime"
+// video_dir = "/videos/anime"
+// target_vmaf = 93
+// config = { encoder = "libsvtav1", max_height = 1080 }
+#[derive(Deserialize, Debug)]
+struct ProfilesFile {
+    profiles: Vec<Profile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Profile {
+    name: String,
+    video_dir: PathBuf,
+    target_vmaf: u8,
+    // on top of config.toml, only what differs for this profile
+    #[serde(default)]
+    config: toml::Table,
+}
+
+impl Profile {
+    fn apply(&self, config: &Config) -> Result<Config> {
+        let mut table = toml::Table::try_from(config)?;
+        table.extend(self.config.clone());
+        let config: Config = table.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+// runs all for each profile in turn, true when any of them had failures
+fn run_profiles_command(opts: ProfilesOpts, config: Config) -> Result<bool> {
+    let profiles_path = match opts.profiles_path {
+        Some(profiles_path) => profiles_path,
+        None => home_dir().expect("home directory must exist").join(".config").join(crate_name!()).join("profiles.toml"),
+    };
+    let profiles_str = fs::read_to_string(&profiles_path)?;
+    let profiles_file: ProfilesFile = toml::from_str(&profiles_str).map_err(|e| Error::InvalidProfiles(profiles_path.clone(), e.to_string()))?;
+
+    // all checked before the first run, so a typo in the last profile doesn't surface hours later
+    let mut profile_configs = Vec::new();
+    for profile in &profiles_file.profiles {
+        let profile_config = profile.apply(&config).map_err(|e| Error::InvalidProfiles(profiles_path.clone(), format!("profile {:?}: {}", profile.name, e)))?;
+        if profile_configs.first().is_some_and(|first_config: &Config| first_config.history_db != profile_config.history_db) {
+            log::warn!("Profile {:?} sets another history_db, the first profile's is used for the whole invocation", profile.name);
+        }
+        profile_configs.push(profile_config);
+    }
+
+    let mut has_failures = false;
+    for (profile, profile_config) in profiles_file.profiles.into_iter().zip(profile_configs) {
+        println!("Running profile {}: {}", profile.name, profile.video_dir.display());
+        let opts = AllOpts {
+            video_dirs: vec![profile.video_dir],
+            target_vmaf: profile.target_vmaf,
+            max_runtime: None,
+            interrupt_at_max_runtime: false,
+            confirm_delete_junk: false,
+            force_reencode: false,
+            emit_scripts: None,
+        };
+        let summary = run_all(opts, profile_config)?;
+        has_failures |= summary.has_failures();
+    }
+    Ok(has_failures)
+}
+
+fn run_review_command(opts: ReviewOpts, config: Config) -> Result<()> {
+    let review_list = ReviewList::load(config.review_list.as_ref().ok_or(Error::ReviewListNotConfigured)?)?;
+    match opts.action {
+        ReviewAction::Add { video_paths } => {
+            for video_path in video_paths {
+                if review_list.add(&video_path, ReviewReason::Manual)? {
+                    println!("Added: {}", video_path.display());
+                } else {
+                    println!("Already on the review list: {}", video_path.display());
+                }
+            }
+        },
+        ReviewAction::Remove { video_paths } => {
+            for video_path in video_paths {
+                if review_list.remove(&video_path)? {
+                    println!("Removed: {}", video_path.display());
+                } else {
+                    println!("Not on the review list: {}", video_path.display());
+                }
+            }
+        },
+        ReviewAction::List => {
+            let entries = review_list.entries.lock().expect("review list lock must not be poisoned");
+            let mut entries: Vec<_> = entries.iter().collect();
+            entries.sort_by(|a, b| a.1.1.cmp(&b.1.1));
+            for (key, (reason, video_path)) in entries {
+                println!("{} {:<16} {}", key, reason, video_path.display());
+            }
+        },
+    }
+    Ok(())
+}
+
+fn run_history_command(opts: HistoryOpts, config: Config) -> Result<()> {
+    let history_db = config.history_db.as_ref().ok_or(Error::HistoryDbNotConfigured)?;
+    let history_db = HistoryDb::open(history_db)?;
+    let connection = history_db.connection.lock().expect("history lock must not be poisoned");
+
+    let mut statement = connection.prepare("SELECT outcome, COUNT(*) FROM files GROUP BY outcome ORDER BY outcome")?;
+    let outcome_counts = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    println!("Files:");
+    for (outcome, count) in outcome_counts {
+        println!("  {}: {}", outcome, count);
+    }
+
+    let (output_count, source_bytes, output_bytes) = connection.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(source_size), 0), COALESCE(SUM(output_size), 0) FROM outputs",
+        [],
+        |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?, row.get::<_, u64>(2)?)),
+    )?;
+    println!("Outputs: {}", output_count);
+    println!("  sources: {:.2} GB", source_bytes as f64 / 1_000_000_000.0);
+    println!("  outputs: {:.2} GB", output_bytes as f64 / 1_000_000_000.0);
+    println!("  saved: {:.2} GB", (source_bytes as f64 - output_bytes as f64) / 1_000_000_000.0);
+    // sources without a reference size count as their own original
+    let reference_bytes: u64 = connection.query_row("SELECT COALESCE(SUM(COALESCE(reference_size, source_size)), 0) FROM outputs", [], |row| row.get(0))?;
+    if reference_bytes != source_bytes {
+        println!("  originals: {:.2} GB", reference_bytes as f64 / 1_000_000_000.0);
+        println!("  saved against originals: {:.2} GB", (reference_bytes as f64 - output_bytes as f64) / 1_000_000_000.0);
+    }
+
+    let reencoded: u64 = connection.query_row(
+        "SELECT COUNT(*) FROM (SELECT output_path FROM outputs GROUP BY output_path HAVING COUNT(*) > 1)",
+        [],
+        |row| row.get(0),
+    )?;
+    println!("Re-encoded outputs: {}", reencoded);
+
+    if opts.failures {
+        let mut statement = connection.prepare("
+            SELECT source_path FROM files AS latest
+            WHERE outcome = 'failed' AND recorded_at = (SELECT MAX(recorded_at) FROM files WHERE source_path = latest.source_path)
+            GROUP BY source_path ORDER BY source_path
+        ")?;
+        let failed_paths = statement.query_map([], |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+        println!("Failures:");
+        for failed_path in failed_paths {
+            println!("  {}", failed_path);
+        }
+    }
+    Ok(())
+}
+
+// Checks a fresh install end to end on a synthetic clip, so no real video is needed
+fn run_self_test_command(opts: SelfTestOpts, mut config: Config) -> Result<()> {
+    // only the plain single file output is checked
+    config.hls = None;
+    config.denoise = None;
+    let test_dir = config.encoding_video_dir().join("self-test");
+    fs::create_dir_all(&test_dir)?;
+    let clip_path = test_dir.join("clip.mkv");
+    let force_crf_path = test_dir.join("force-crf").with_extension(&config.output_extension);
+    let ab_av1_path = test_dir.join("ab-av1").with_extension(&config.output_extension);
+    let inherited_log_level = env::var("RUST_LOG").unwrap_or("warn".to_string());
+    println!("Encoder: {}{}", config.encoder, if is_nvenc_encoder(&config.encoder) { " (NVENC)" } else { "" });
+
+    let mut checks = 0;
+    let mut failures = 0;
+    let mut check = |name: &str, result: Result<String>| -> bool {
+        checks += 1;
+        match result {
+            Ok(detail) => {
+                println!("PASS {}: {}", name, detail);
+                true
+            },
+            Err(e) => {
+                failures += 1;
+                println!("FAIL {}: {:?}", name, e);
+                false
+            },
+        }
+    };
+
+    let clip_ok = check("test clip (ffmpeg lavfi)", exec_test_clip_ffmpeg(&clip_path, opts.secs).map(|_| clip_path.display().to_string()))
+        && check("probe (ffprobe)", probe_video(&clip_path).map(|probe| format!("{}x{}, {:?} sec", probe.width, probe.height, probe.duration_secs)));
+    if clip_ok {
+        let crf = config.min_crf + (config.max_crf - config.min_crf) / 2;
+        let result = exec_force_crf_ffmpeg(&clip_path, &force_crf_path, crf, None, None, &config)
+            .and_then(|_| self_test_output_detail(&force_crf_path, &config));
+        check(&format!("force crf (ffmpeg {})", config.encoder), result);
+
+        let result = exec_ab_av1(&clip_path, &ab_av1_path, opts.target_vmaf, None, None, false, &inherited_log_level, &config)
+            .and_then(|stats| Ok(format!("{}, VMAF {:?} with crf {:?}", self_test_output_detail(&ab_av1_path, &config)?, stats.vmaf, stats.crf)));
+        check(&format!("auto encode (ab-av1 {})", config.encoder), result);
+    } else {
+        println!("SKIP encodes, as there is no test clip");
+    }
+
+    fs::remove_dir_all(&test_dir)?;
+    if failures > 0 {
+        return Err(anyhow!(Error::SelfTestFailed(failures, checks)));
+    }
+    println!("All {} checks passed", checks);
+    Ok(())
+}
+
+fn self_test_output_detail(output_path: impl AsRef<Path>, config: &Config) -> Result<String> {
+    let output_path = output_path.as_ref();
+    if !is_valid_video_file(output_path, config)? {
+        return Err(anyhow!(Error::FfprobeCheckValidVideoFailed(format!("Invalid output: {:?}", output_path))));
+    }
+    check_output_duration(output_path, config)?;
+    Ok(format!("{} bytes", fs::metadata(output_path)?.len()))
+}
+
+// lossless ffv1 and pcm, so the clip itself doesn't depend on any optional encoder
+fn exec_test_clip_ffmpeg(output_path: impl AsRef<Path>, secs: u32) -> Result<()> {
+    let output_path = output_path.as_ref();
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-y")
+        .arg("-f").arg("lavfi").arg("-i").arg(format!("testsrc2=duration={}:size=1280x720:rate=30", secs))
+        .arg("-f").arg("lavfi").arg("-i").arg(format!("sine=frequency=440:duration={}", secs))
+        .arg("-c:v").arg("ffv1").arg("-pix_fmt").arg("yuv420p")
+        .arg("-c:a").arg("pcm_s16le")
+        .arg(output_path);
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::SelfTestClipFailed(format!("{:?}", e)))))?;
+    log::debug!("Command status: {:?}", output.status);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::SelfTestClipFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
+    }
+    Ok(())
+}
+
+// encodes a sample per crf and shows its size and VMAF until a crf is accepted,
+// then encodes the whole video with it like force-crf-single
+fn run_tune_crf_command(opts: TuneOpts, config: Config) -> Result<()> {
+    let video_path = &opts.video_path;
+    let encodnig_video_dir = config.encoding_video_dir();
+    fs::create_dir_all(&encodnig_video_dir)?;
+
+    let probe = probe_video(video_path)?;
+    log::debug!("Probe: {:?}", probe);
+    let duration_secs = probe.duration_secs.unwrap_or(opts.sample_secs);
+    let sample_range = sample_range(duration_secs, opts.sample_secs, opts.sample_start);
+    let sample_secs = sample_range.1 - sample_range.0;
+    let sample_path = suffixed_encoding_video_path(&encodnig_video_dir, &hash_file_location(video_path), "tune", &config);
+
+    let mut crf = opts.crf;
+    loop {
+        println!("Encoding sample ({:.2} - {:.2} sec) with crf {} ...", sample_range.0, sample_range.1, crf);
+        exec_force_crf_ffmpeg(video_path, &sample_path, crf, downscale_height(probe.filter_height(&config), config.max_height), Some(sample_range), &config)?;
+        let sample_size = fs::metadata(&sample_path)?.len();
+        let vmaf = measure_vmaf(&sample_path, video_path, Some(sample_range))?;
+        let estimated_size = sample_size as f64 * duration_secs / sample_secs;
+        println!("crf {}: VMAF {:.2}, sample {:.2} MB, estimated full size {:.2} MB", crf, vmaf, sample_size as f64 / 1_000_000.0, estimated_size / 1_000_000.0);
+
+        let next_crf = loop {
+            print!("Next crf (empty to accept crf {}, q to quit): ", crf);
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            let line = line.trim();
+            if line.is_empty() {
+                break None;
+            }
+            if line == "q" {
+                fs::remove_file(&sample_path)?;
+                return Ok(());
+            }
+            match line.parse::<u8>() {
+                Ok(next_crf) => break Some(next_crf),
+                Err(_) => println!("Invalid crf: {}", line),
+            }
+        };
+        fs::remove_file(&sample_path)?;
+        match next_crf {
+            Some(next_crf) => crf = next_crf,
+            None => break,
+        }
+    }
+
+    println!("Accepted crf {}", crf);
+    run_force_crf_single_command(ForceCrfSingleOpts { video_path: opts.video_path, crf, output: None, video_stream: None, audio_stream: None }, config)
+}
+
+// encodes the same sample at each crf step, for picking min_crf and max_crf
+fn run_crf_sweep_command(opts: SweepOpts, config: Config) -> Result<()> {
+    let video_path = &opts.video_path;
+    let encodnig_video_dir = config.encoding_video_dir();
+    fs::create_dir_all(&encodnig_video_dir)?;
+
+    let probe = probe_video(video_path)?;
+    log::debug!("Probe: {:?}", probe);
+    let duration_secs = probe.duration_secs.unwrap_or(opts.sample_secs);
+    let sample_range = sample_range(duration_secs, opts.sample_secs, opts.sample_start);
+    let sample_path = suffixed_encoding_video_path(&encodnig_video_dir, &hash_file_location(video_path), "sweep", &config);
+
+    let mut results = Vec::new();
+    for crf in (config.min_crf..=config.max_crf).step_by(opts.step.max(1) as usize) {
+        println!("Encoding sample ({:.2} - {:.2} sec) with crf {} ...", sample_range.0, sample_range.1, crf);
+        exec_force_crf_ffmpeg(video_path, &sample_path, crf, downscale_height(probe.filter_height(&config), config.max_height), Some(sample_range), &config)?;
+        let sample_size = fs::metadata(&sample_path)?.len();
+        let vmaf = measure_vmaf(&sample_path, video_path, Some(sample_range))?;
+        fs::remove_file(&sample_path)?;
+        results.push((crf, sample_size, vmaf));
+    }
+
+    println!("{:>4} {:>14} {:>8}", "crf", "size", "vmaf");
+    for (crf, sample_size, vmaf) in &results {
+        println!("{:>4} {:>14} {:>8.2}", crf, sample_size, vmaf);
+    }
+
+    if let Some(csv_path) = &opts.csv {
+        let mut csv = String::from("crf,size,vmaf\n");
+        for (crf, sample_size, vmaf) in &results {
+            csv.push_str(&format!("{},{},{:.4}\n", crf, sample_size, vmaf));
+        }
+        fs::write(csv_path, csv)?;
+        println!("Wrote results to: {}", csv_path.display());
+    }
+
+    Ok(())
+}
+
+// extrapolates one sample encode to the whole video, for deciding which long videos are worth queuing
+fn run_estimate_size_command(opts: EstimateOpts, config: Config) -> Result<()> {
+    let video_path = &opts.video_path;
+    let sidecar = Sidecar::load(video_path)?.unwrap_or_default();
+    let mut config = sidecar.apply(&config)?;
+    config.output_extension = config.output_extension_for(video_path);
+    let encodnig_video_dir = config.encoding_video_dir();
+    fs::create_dir_all(&encodnig_video_dir)?;
+
+    let crf = opts.crf.or(sidecar.crf).unwrap_or((config.min_crf as u16 + config.max_crf as u16).div_ceil(2) as u8);
+    let probe = probe_video(video_path)?;
+    log::debug!("Probe: {:?}", probe);
+    let duration_secs = rough_video_secs(video_path, &config)?.ok_or(Error::FfprobeProbeVideoFailed(format!("No duration: {:?}", video_path)))?;
+    let sample_range = sample_range(duration_secs, opts.sample_secs, opts.sample_start);
+    let sample_secs = sample_range.1 - sample_range.0;
+    let sample_path = suffixed_encoding_video_path(&encodnig_video_dir, &hash_file_location(video_path), "estimate", &config);
+
+    println!("Encoding sample ({:.2} - {:.2} sec) with crf {} ...", sample_range.0, sample_range.1, crf);
+    let result = exec_force_crf_ffmpeg(video_path, &sample_path, crf, downscale_height(probe.filter_height(&config), config.max_height), Some(sample_range), &config);
+    let sample_size = result.and_then(|()| Ok(fs::metadata(&sample_path)?.len()));
+    if sample_path.exists() {
+        fs::remove_file(&sample_path)?;
+    }
+    let sample_size = sample_size?;
+
+    let source_size = fs::metadata(video_path)?.len();
+    let bytes_per_sec = sample_size as f64 / sample_secs;
+    let estimated_size = bytes_per_sec * duration_secs;
+    println!("Sample: {:.2} MB in {:.2} sec ({:.0} kbps)", sample_size as f64 / 1_000_000.0, sample_secs, bytes_per_sec * 8.0 / 1000.0);
+    println!("Estimated size: {:.2} MB of {:.2} MB source ({:.1}%), saves {:.2} MB",
+        estimated_size / 1_000_000.0, source_size as f64 / 1_000_000.0, estimated_size * 100.0 / source_size as f64, (source_size as f64 - estimated_size) / 1_000_000.0);
+    Ok(())
+}
+
+// needs the sources, so only works for files encoded with keep_original
+fn run_audit_command(opts: AuditOpts, config: Config) -> Result<()> {
+    let inherited_log_level = env::var("RUST_LOG").unwrap_or("warn".to_string());
+    let encodnig_video_dir = config.encoding_video_dir();
+    fs::create_dir_all(&encodnig_video_dir)?;
+
+    let mut reencoded = 0;
+    for video_path in jdt::walk_dir(&opts.video_dir, |path| path) {
+        if is_junk(&video_path) || !guess_video_file(&video_path) {
+            continue;
+        }
+        let sidecar = Sidecar::load(&video_path)?.unwrap_or_default();
+        if sidecar.skip {
+            continue;
+        }
+        let mut config = sidecar.apply(&config)?;
+        config.output_extension = config.output_extension_for(&video_path);
+        let save_path = encoded_file_save_path(&video_path, &config)?;
+        if !save_path.exists() {
+            continue;
+        }
+
+        let vmaf = measure_vmaf(&save_path, &video_path, None)?;
+        if vmaf >= opts.min_vmaf {
+            println!("VMAF {:.2}: {}", vmaf, save_path.display());
+            continue;
+        }
+
+        let target_vmaf = (opts.min_vmaf.ceil() as u8).saturating_add(opts.bump).min(100);
+        println!("VMAF {:.2} below {:.2}, re-encoding with target VMAF {}: {}", vmaf, opts.min_vmaf, target_vmaf, video_path.display());
+        let encoding_video_path = suffixed_encoding_video_path(&encodnig_video_dir, &hash_file_location(&video_path), "audit", &config);
+        if encoding_video_path.exists() {
+            return Err(anyhow!(Error::ConflictVideoEncoding(video_path.clone(), encoding_video_path)));
+        }
+
+        let probe = probe_video(&video_path)?;
+        log::debug!("Probe: {:?}", probe);
+        let result = exec_ab_av1(&video_path, &encoding_video_path, target_vmaf, downscale_height(probe.filter_height(&config), config.max_height), max_encoded_percent_for_height(probe.height, &config), false, &inherited_log_level, &config);
+        match result {
+            Ok(_) => {},
+            Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::AbAv1CommandFailed(_) | Error::VmafTargetUnreachable(_) | Error::MaxEncodedPercentExceeded(_))) => {
+                println!("Re-encoding failed, keeping the current output: {}", save_path.display());
+                if encoding_video_path.exists() {
+                    fs::remove_file(&encoding_video_path)?;
+                }
+                continue;
+            },
+            Err(e) => {
+                if encoding_video_path.exists() {
+                    fs::remove_file(&encoding_video_path)?;
+                }
+                return Err(e);
+            },
+        }
+
+        let reencoded_vmaf = measure_vmaf(&encoding_video_path, &video_path, None)?;
+        if reencoded_vmaf <= vmaf || !is_valid_video_file(&encoding_video_path, &config)? {
+            println!("Re-encoded VMAF {:.2} is no better, keeping the current output: {}", reencoded_vmaf, save_path.display());
+            fs::remove_file(&encoding_video_path)?;
+            continue;
+        }
+
+        println!("Replacing output with re-encoded VMAF {:.2}: {}", reencoded_vmaf, save_path.display());
+        // the current output stays until the re-encode is in place, a failed copy must not lose both
+        replace_in_save_dir(&encoding_video_path, &save_path, &config)?;
+        reencoded += 1;
+    }
+    println!("{} outputs re-encoded", reencoded);
+    Ok(())
+}
+
+const IMAGE_SEQUENCE_SIZE_SAMPLES: usize = 20;
+
+fn run_image_sequence_command(opts: SeqOpts, config: Config) -> Result<()> {
+    let output_path = config.save_dir.join(&opts.output_path);
+    if output_path.exists() {
+        return Err(anyhow!(Error::SingleEncodeSavePathAlreadyExists(output_path)));
+    }
+    if let Some(output_dir) = output_path.parent() {
+        create_save_dir(output_dir, &config)?;
+    }
+
+    let frame_paths = glob_file_paths(&opts.pattern)?;
+    if frame_paths.is_empty() {
+        return Err(anyhow!(Error::ImageSequenceNoFrames(opts.pattern.clone())));
+    }
+    println!("Found {} frames", frame_paths.len());
+
+    // probing every frame of a long render takes minutes, evenly spaced samples catch a resized render
+    let sample_step = frame_paths.len().div_ceil(IMAGE_SEQUENCE_SIZE_SAMPLES).max(1);
+    let sample_paths = frame_paths.iter().step_by(sample_step).chain(frame_paths.last());
+    let mut first_size = None;
+    for frame_path in sample_paths {
+        let probe = probe_video(frame_path)?;
+        match first_size {
+            None => first_size = Some((probe.width, probe.height)),
+            Some((width, height)) if (width, height) != (probe.width, probe.height) => {
+                return Err(anyhow!(Error::ImageSequenceInconsistentSize(frame_path.clone(), probe.width, probe.height, width, height)));
+            },
+            Some(_) => {},
+        }
+    }
+
+    let encodnig_video_dir = config.encoding_video_dir_for(output_path.parent().unwrap_or(&config.save_dir));
+    fs::create_dir_all(&encodnig_video_dir)?;
+    let encoding_video_path = suffixed_encoding_video_path(&encodnig_video_dir, &hash_file_location(&output_path), "sequence", &config);
+    if encoding_video_path.exists() {
+        return Err(anyhow!(Error::ConflictVideoEncoding(PathBuf::from(&opts.pattern), encoding_video_path)));
+    }
+
+    println!("Encoding image sequence: {}", opts.pattern);
+    if let Err(e) = exec_image_sequence_ffmpeg(&opts.pattern, opts.framerate, &encoding_video_path, opts.crf, &config) {
+        if encoding_video_path.exists() {
+            fs::remove_file(&encoding_video_path)?;
+        }
+        return Err(e);
+    }
+    if !is_valid_video_file(&encoding_video_path, &config)? {
+        fs::remove_file(&encoding_video_path)?;
+        return Err(anyhow!(Error::SingleEncodeFailedWithInvalidEncodedFile(PathBuf::from(&opts.pattern), encoding_video_path)));
+    }
+
+    println!("Saving video to: {}", output_path.display());
+    rename_into_save_dir(&encoding_video_path, &output_path, &config)?;
+    Ok(())
+}
+
+// only the file name may have wildcards, "*" for any run of characters and "?" for one
+fn glob_file_paths(pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern_path = Path::new(pattern);
+    let dir = match pattern_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let filename_pattern = pattern_path.file_name().map(|filename| filename.to_string_lossy().to_string()).unwrap_or_default();
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && wildcard_match(&filename_pattern, &entry.file_name().to_string_lossy()) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // classic backtracking over the last "*", linear for patterns with a single "*"
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// re-applies the current naming to existing outputs, e.g. after changing the renamer
+fn run_rename_command(opts: RenameOpts, config: Config) -> Result<()> {
+    let output_extensions: HashSet<&str> = std::iter::once(&config.output_extension).chain(config.output_extension_by_input.values()).map(|extension| extension.as_str()).collect();
+    let mut renamed = 0;
+    for saved_path in jdt::walk_dir(&config.save_dir, |path| path) {
+        let Some(saved_extension) = saved_path.extension().and_then(|extension| extension.to_str()).filter(|extension| output_extensions.contains(extension)) else {
+            continue;
+        };
+        // a saved file keeps its container, whichever source extension it came from
+        let mut config = config.clone();
+        config.output_extension = saved_extension.to_string();
+        let saved_filename = saved_path.file_name().ok_or(Error::InvalidVideoPath(saved_path.to_path_buf()))?;
+        let saved_dir = saved_path.parent().ok_or(Error::InvalidVideoPath(saved_path.to_path_buf()))?;
+
+        let pre_rename_path = saved_dir.join(destination_filename(strip_output_affixes(saved_filename, &config), &config)?);
+        if pre_rename_path == saved_path {
+            continue;
+        }
+        let rename_path = saved_dir.join(rename_for_linux_limit::new_filename(&pre_rename_path, Some(saved_dir))?);
+        if rename_path == saved_path {
+            continue;
+        }
+        if rename_path.exists() {
+            println!("Skipping, destination already exists: {} -> {}", saved_path.display(), rename_path.display());
+            continue;
+        }
+
+        println!("Renaming: {} -> {}", saved_path.display(), rename_path.display());
+        if !opts.dry_run {
+            check_save_dir_writable(saved_dir, &config)?;
+            fs::rename(&saved_path, &rename_path)?;
+        }
+        renamed += 1;
+    }
+    println!("{} files {}", renamed, if opts.dry_run { "to rename" } else { "renamed" });
+    Ok(())
+}
+
+// (start, end) of a sample, centered in the video unless sample_start is given
+fn sample_range(duration_secs: f64, sample_secs: f64, sample_start: Option<f64>) -> (f64, f64) {
+    let sample_secs = sample_secs.min(duration_secs);
+    let sample_start = sample_start.unwrap_or(((duration_secs - sample_secs) / 2.0).max(0.0));
+    (sample_start, sample_start + sample_secs)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn exec_ab_av1(input_path: impl AsRef<Path>, output_path: impl AsRef<Path>, target_vmaf: u8, scale_height: Option<u32>, max_encoded_percent: Option<u8>, debug_intermediate_files: bool, log_level: impl AsRef<str>, config: &Config) -> Result<AbAv1Stats> {
+    let input_path = input_path.as_ref();
+    let output_path = output_path.as_ref();
+    let log_level = log_level.as_ref();
+    // --keep leaves the intermediate files in the working directory to be looked at
+    let sample_dir = if debug_intermediate_files { None } else { Some(SampleDir::create(output_path, config)?) };
+    let tmp_dir = sample_dir.as_ref().map_or(PathBuf::from("."), |sample_dir| sample_dir.path.clone());
+    let mut command = Command::new("ab-av1");
+    command
+        .env("RUST_BACKTRACE", "1")
+        .env("RUST_LOG", format!("ab_av1={}", log_level))
+        .arg(if config.use_ab_av1_crf_with_ffmpeg { "crf-search" } else { "auto-encode" })
+        .arg("-e").arg(&config.encoder);
+    let rotation = video_rotation(input_path, config)?;
+    let bake_rotation = rotation != 0 && config.rotation_mode == RotationMode::Bake;
+    if is_nvenc_encoder(&config.encoder) {
+        add_cuda_device_env(&mut command);
+        // CUDA frames can't go through ffmpeg's autorotate filter or the CPU denoise filters,
+        // so those sources are decoded on the CPU
+        if !bake_rotation && config.denoise.is_none() {
+            command.arg("--cuda");
+        }
+        command
+            .arg("--enc").arg("v:b=0").arg("--enc").arg("rc=vbr")
+            .arg("--enc").arg("temporal-aq=1")
+            .arg("--enc").arg("tune=hq")
+            .arg("--enc").arg("rc-lookahead=32")
+            .arg("--preset").arg("p7");
+        if let Some(min_bitrate) = &config.min_bitrate {
+            command.arg("--enc").arg(format!("minrate={}", min_bitrate));
+        }
+    }
+    for (option, value) in encoder_opts(config) {
+        command.arg("--enc").arg(format!("{}={}", option, value));
+    }
+    for (key, value) in &config.output_metadata {
+        command.arg("--enc").arg(format!("metadata={}={}", key, value));
+    }
+    if !config.output_metadata.is_empty() && config.output_extension.eq_ignore_ascii_case("mp4") {
+        command.arg("--enc").arg("movflags=+use_metadata_tags");
+    }
+    if is_experimental_audio_encoder(&config.audio_codec) {
+        command.arg("--enc").arg("strict=experimental");
+    }
+    if let Some(ffmpeg_threads) = config.ffmpeg_threads {
+        command
+            .arg("--enc-input").arg(format!("threads={}", ffmpeg_threads))
+            .arg("--enc").arg(format!("threads={}", ffmpeg_threads))
+            .arg("--vmaf").arg(format!("n_threads={}", ffmpeg_threads));
+    }
+    if rotation != 0 && config.rotation_mode == RotationMode::Preserve {
+        command
+            .arg("--enc-input").arg("noautorotate")
+            .arg("--enc").arg(format!("metadata:s:v:0=rotate={}", rotation));
+    }
+    command
+        .arg("--enc").arg("fps_mode=passthrough")
+        .arg("--enc").arg("sn").arg("--enc").arg("dn")
+        .arg("--min-vmaf").arg(target_vmaf.to_string())
+        .arg("--min-crf").arg(config.min_crf.to_string())
+        .arg("--max-crf").arg(config.max_crf.to_string())
+        .arg("--temp-dir").arg(tmp_dir)
+        .arg("-i").arg(input_path);
+    if !config.use_ab_av1_crf_with_ffmpeg {
+        command
+            .arg("--acodec").arg(&config.audio_codec)
+            .arg("-o").arg(output_path);
+    }
+
+    if let Some(max_encoded_percent) = max_encoded_percent {
+        command.arg("--max-encoded-percent").arg(max_encoded_percent.to_string());
+    }
+    // denoised before scaling, so the grain doesn't get baked into the downscaled detail
+    let video_filters: Vec<String> = config.denoise.iter().map(DenoiseConfig::filter)
+        .chain(scale_height.map(|scale_height| format!("scale=-2:{}", scale_height)))
+        .collect();
+    if !video_filters.is_empty() {
+        command.arg("--vfilter").arg(video_filters.join(","));
+    }
+    if debug_intermediate_files {
+        command.arg("--keep");
+    }
+    // stderr is teed to tell the failure reason and the achieved VMAF afterwards
+    command.stderr(Stdio::piped());
+    log::debug!("Command: {}", command_line(&command));
+    emit_encode_command(&command)?;
+    let log_file = open_ab_av1_log(&command)?;
+    let gpu_session = acquire_gpu_session_permit(config);
+    // crf-search prints its result to stdout
+    if log_file.is_some() || config.use_ab_av1_crf_with_ffmpeg {
+        command.stdout(Stdio::piped());
+    }
+    let mut child = command.spawn().map_err(|e| spawn_error(&command, e, anyhow::Error::from))?;
+    let stderr = child.stderr.take().expect("stderr must be piped");
+    let file_label = file_label();
+    let stdout_thread = child.stdout.take().map(|stdout| {
+        let (file_label, log_file) = (file_label.clone(), log_file.clone());
+        thread::spawn(move || {
+            // reported like the worker's own lines
+            set_file_label(file_label);
+            tee_stdout_lines(stdout, log_file)
+        })
+    });
+    let stderr_thread = thread::spawn(move || tee_stderr_lines(stderr, file_label, log_file));
+    let status = wait_encoder(child, output_path)?;
+    // released before the ffmpeg encode after crf-search, which takes its own
+    drop(gpu_session);
+    let stderr_lines = stderr_thread.join().expect("stderr thread must not panic");
+    let stdout_lines = stdout_thread.map(|stdout_thread| stdout_thread.join().expect("stdout thread must not panic")).unwrap_or_default();
+    log::debug!("Command status: {:?}", status);
+    if status.success() && config.use_ab_av1_crf_with_ffmpeg {
+        let stats = parse_ab_av1_stats(&[stderr_lines, stdout_lines].concat());
+        let crf = stats.crf.ok_or(Error::CrfSearchWithoutCrf(input_path.to_path_buf()))?;
+        report!("Encoding with crf {} found by crf-search: {}", crf, input_path.display());
+        exec_force_crf_ffmpeg(input_path, output_path, crf.round() as u8, scale_height, None, config)?;
+        Ok(stats)
+    } else if status.success() {
+        Ok(parse_ab_av1_stats(&stderr_lines))
+    } else if max_encoded_percent.is_some_and(|max_encoded_percent| exceeded_max_encoded_percent(&stderr_lines, max_encoded_percent)) {
+        Err(anyhow!(Error::MaxEncodedPercentExceeded(status)))
+    } else if stderr_lines.iter().any(|line| line.contains(AB_AV1_NO_GOOD_CRF_MESSAGE)) {
+        Err(anyhow!(Error::VmafTargetUnreachable(status)))
+    } else {
+        Err(anyhow!(Error::AbAv1CommandFailed(status)))
+    }
+}
+
+const AB_AV1_NO_GOOD_CRF_MESSAGE: &str = "Failed to find a suitable crf";
+
+#[derive(Debug, Default, Clone, Copy)]
+struct AbAv1Stats {
+    crf: Option<f32>,
+    vmaf: Option<f64>,
+}
+
+fn tee_stderr_lines(stderr: impl Read, file_label: Option<String>, log_file: Option<Arc<AppendWriter>>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in BufReader::new(stderr).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        match &file_label {
+            Some(file_label) => eprintln!("{} {}", file_label, line),
+            None => eprintln!("{}", line),
+        }
+        if let Some(log_file) = &log_file {
+            log_file.append_line(line.as_str());
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn tee_stdout_lines(stdout: impl Read, log_file: Option<Arc<AppendWriter>>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in BufReader::new(stdout).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        report!("{}", line);
+        if let Some(log_file) = &log_file {
+            log_file.append_line(line.as_str());
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// both streams go into one file in the order they arrive, after the command that produced them
+fn open_ab_av1_log(command: &Command) -> Result<Option<Arc<AppendWriter>>> {
+    let Some(log_path) = AB_AV1_LOG.with(|current| current.borrow().clone()) else {
+        return Ok(None);
+    };
+    let log_file = AppendWriter::open(&log_path)?;
+    log_file.append_line(format!("$ {}", command_line(command)));
+    Ok(Some(Arc::new(log_file)))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CrfSearchAttempt {
+    crf: f32,
+    vmaf: f64,
+    encoded_percent: Option<f64>,
+}
+
+// every sampled crf in the order tried, ab-av1 repeats a line as its progress updates, so the last one for a crf wins
+fn parse_crf_search_trace(lines: &[String]) -> Vec<CrfSearchAttempt> {
+    let mut attempts: Vec<CrfSearchAttempt> = Vec::new();
+    for line in lines {
+        let stats = parse_ab_av1_stats(std::slice::from_ref(line));
+        let (Some(crf), Some(vmaf)) = (stats.crf, stats.vmaf) else {
+            continue;
+        };
+        let encoded_percent = line.split_whitespace()
+            .filter_map(|word| word.strip_prefix('(').and_then(|word| word.strip_suffix("%)")).and_then(|percent| percent.parse::<f64>().ok()))
+            .next_back();
+        let attempt = CrfSearchAttempt { crf, vmaf, encoded_percent };
+        match attempts.iter_mut().find(|attempt| attempt.crf == crf) {
+            Some(existing) => *existing = attempt,
+            None => attempts.push(attempt),
+        }
+    }
+    attempts
+}
+
+// ab-av1 gives up with the same message whether VMAF or size ruled out every crf,
+// so it's the size of the last sample encode, e.g. "predicted video stream size 1.2 GiB (85%)", that tells
+fn exceeded_max_encoded_percent(lines: &[String], max_encoded_percent: u8) -> bool {
+    if !lines.iter().any(|line| line.contains(AB_AV1_NO_GOOD_CRF_MESSAGE)) {
+        return false;
+    }
+    let last_percent = lines.iter().flat_map(|line| line.split_whitespace())
+        .filter_map(|word| word.strip_prefix('(').and_then(|word| word.strip_suffix("%)")).and_then(|percent| percent.parse::<f64>().ok()))
+        .next_back();
+    last_percent.is_some_and(|last_percent| last_percent > max_encoded_percent as f64)
+}
+
+// ab-av1 reports the chosen sample like "crf 28 VMAF 95.12 predicted video stream size ..."
+fn parse_ab_av1_stats(lines: &[String]) -> AbAv1Stats {
+    let mut stats = AbAv1Stats::default();
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        for pair in words.windows(2) {
+            match pair[0] {
+                "crf" => stats.crf = pair[1].parse().ok().or(stats.crf),
+                "VMAF" => stats.vmaf = pair[1].parse().ok().or(stats.vmaf),
+                _ => {},
+            }
+        }
+    }
+    stats
+}
+
+// VMAF sometimes gives wrong results than human-sense score, for example, the reference video with VHD frame-vibrations, etc.
+// So, we support the feature just to set constant quality for ffmpeg
+fn exec_force_crf_ffmpeg(input_path: impl AsRef<Path>, output_path: impl AsRef<Path>, crf: u8, scale_height: Option<u32>, time_range: Option<(f64, f64)>, config: &Config) -> Result<()> {
+    let input_path = input_path.as_ref();
+    let output_path = output_path.as_ref();
+    let encoder = &config.encoder;
+    let rotation = video_rotation(input_path, config)?;
+    // CUDA frames can't go through ffmpeg's autorotate filter or the CPU denoise filters,
+    // so those sources are decoded on the CPU
+    let gpu_decode = is_nvenc_encoder(encoder) && !(rotation != 0 && config.rotation_mode == RotationMode::Bake) && config.denoise.is_none();
+    let mut command = Command::new("ffmpeg");
+    if is_nvenc_encoder(encoder) {
+        add_cuda_device_env(&mut command);
+    }
+    command.arg("-y");
+    if config.force_crf_progress {
+        command.arg("-nostats").arg("-progress").arg("pipe:1").stdout(Stdio::piped());
+    }
+    if gpu_decode {
+        command.arg("-hwaccel").arg("cuda").arg("-hwaccel_output_format").arg("cuda");
+    }
+    if rotation != 0 && config.rotation_mode == RotationMode::Preserve {
+        command.arg("-noautorotate");
+    }
+    if let Some((start_secs, end_secs)) = time_range {
+        command.arg("-ss").arg(start_secs.to_string()).arg("-to").arg(end_secs.to_string());
+    }
+    if config.expand_disc_images && is_disc_image(input_path) {
+        command.arg("-f").arg("dvdvideo");
+    }
+    add_input_probe_args(&mut command, config);
+    command.arg("-i").arg(input_path);
+    let program_id = match config.program {
+        Some(selection) => selected_program(input_path, selection, config)?,
+        None => None,
+    };
+    if let Some(video_stream) = config.video_stream {
+        check_stream_index(input_path, video_stream, "video", config)?;
+    }
+    if let Some(audio_stream) = config.audio_stream {
+        check_stream_index(input_path, audio_stream, "audio", config)?;
+    }
+    let video_filters: Vec<String> = config.denoise.iter().map(DenoiseConfig::filter)
+        .chain(scale_height.map(|scale_height| if gpu_decode {
+            // frames stay on the GPU with -hwaccel_output_format cuda, so scale there too
+            format!("scale_cuda=-2:{}", scale_height)
+        } else {
+            format!("scale=-2:{}", scale_height)
+        }))
+        .collect();
+    if !video_filters.is_empty() {
+        command.arg("-vf").arg(video_filters.join(","));
+    }
+    add_video_encoder_args(&mut command, crf, config);
+    if rotation != 0 && config.rotation_mode == RotationMode::Preserve {
+        command.arg("-metadata:s:v:0").arg(format!("rotate={}", rotation));
+    }
+    command.arg("-sn").arg("-dn");
+    // stream indexes count within the program, so source_track is its n-th audio
+    let input_streams = match program_id {
+        Some(program_id) => format!("0:p:{}", program_id),
+        None => "0".to_string(),
+    };
+    // an explicit stream index wins over the program
+    let video_map = match config.video_stream {
+        Some(video_stream) => format!("0:{}", video_stream),
+        None => format!("{}:v:0", input_streams),
+    };
+    if config.audio_tracks.is_empty() {
+        if program_id.is_some() || config.video_stream.is_some() || config.audio_stream.is_some() {
+            let audio_map = match config.audio_stream {
+                Some(audio_stream) => format!("0:{}", audio_stream),
+                None => format!("{}:a?", input_streams),
+            };
+            command.arg("-map").arg(&video_map).arg("-map").arg(audio_map);
+        }
+        command.arg("-acodec").arg(&config.audio_codec);
+    } else {
+        command.arg("-map").arg(&video_map);
+        for audio_track in &config.audio_tracks {
+            command.arg("-map").arg(format!("{}:a:{}", input_streams, audio_track.source_track));
+        }
+        for (index, audio_track) in config.audio_tracks.iter().enumerate() {
+            command.arg(format!("-c:a:{}", index)).arg(&audio_track.codec);
+            if let Some(bitrate) = &audio_track.bitrate {
+                command.arg(format!("-b:a:{}", index)).arg(bitrate);
+            }
+            if let Some(channels) = audio_track.channels {
+                command.arg(format!("-ac:a:{}", index)).arg(channels.to_string());
+            }
+        }
+    }
+    if config.audio_codecs().into_iter().any(is_experimental_audio_encoder) {
+        command.arg("-strict").arg("experimental");
+    }
+    for (key, value) in &config.output_metadata {
+        command.arg("-metadata").arg(format!("{}={}", key, value));
+    }
+    // mp4 drops tags it doesn't know without this
+    if !config.output_metadata.is_empty() && config.output_extension.eq_ignore_ascii_case("mp4") {
+        command.arg("-movflags").arg("+use_metadata_tags");
+    }
+    // escape hatch for what the config can't express, e.g. -svtav1-params
+    command.args(&config.extra_ffmpeg_output_args);
+    if let Some(hls) = &config.hls {
+        fs::create_dir_all(output_path)?;
+        command
+            .arg("-f").arg("hls")
+            .arg("-hls_time").arg(hls.segment_secs.to_string())
+            .arg("-hls_playlist_type").arg("vod")
+            .arg("-hls_segment_type").arg("fmp4")
+            .arg("-hls_fmp4_init_filename").arg(HLS_INIT_FILENAME)
+            .arg("-hls_segment_filename").arg(output_path.join("segment_%05d.m4s"))
+            .arg(output_path.join(HLS_PLAYLIST_FILENAME));
+    } else {
+        command.arg(output_path);
+    }
+
+    // probe before spawning, as a missing duration just means no percentage
+    let duration_secs = if config.force_crf_progress {
+        match time_range {
+            Some((start_secs, end_secs)) => Some(end_secs - start_secs),
+            None => rough_video_secs(input_path, config).ok().flatten(),
+        }
+    } else {
+        None
+    };
+
+    log::debug!("Command: {}", command_line(&command));
+    emit_encode_command(&command)?;
+    let _gpu_session = acquire_gpu_session_permit(config);
+    let mut child = command.spawn().map_err(|e| spawn_error(&command, e, anyhow::Error::from))?;
+    let file_label = file_label();
+    let progress_thread = child.stdout.take().map(|stdout| thread::spawn(move || show_ffmpeg_progress(stdout, duration_secs, file_label)));
+    let status = wait_encoder(child, output_path)?;
+    if let Some(progress_thread) = progress_thread {
+        let _ = progress_thread.join();
+    }
+    log::debug!("Command status: {:?}", status);
+    if status.success() {
+       Ok(())
+    } else {
+        Err(anyhow!(Error::ForceCrfFfmpegCommandFailed(status)))
+    }
+}
+
+// parses "key=value" lines of ffmpeg -progress, out_time_us is the encoded position
+fn show_ffmpeg_progress(stdout: impl Read, duration_secs: Option<f64>, file_label: Option<String>) {
+    let progress_bar = match duration_secs {
+        Some(duration_secs) => {
+            let progress_bar = ProgressBar::new((duration_secs * 1000.0) as u64);
+            progress_bar.set_style(ProgressStyle::with_template("{prefix}{wide_bar} {percent}% {msg} (eta {eta})").expect("progress template must be valid"));
+            progress_bar
+        },
+        None => ProgressBar::new_spinner(),
+    };
+    let progress_bar = match MULTI_PROGRESS.get() {
+        Some(multi_progress) => multi_progress.add(progress_bar),
+        None => progress_bar,
+    };
+    if let Some(file_label) = file_label {
+        progress_bar.set_prefix(format!("{} ", file_label));
+    }
+    for line in BufReader::new(stdout).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match key {
+            // out_time_ms is also microseconds despite its name
+            "out_time_us" | "out_time_ms" => {
+                if let Ok(out_time_us) = value.trim().parse::<u64>() {
+                    progress_bar.set_position(out_time_us / 1000);
+                }
+            },
+            "frame" => progress_bar.set_message(format!("frame {}", value.trim())),
+            "progress" if value.trim() == "end" => break,
+            _ => {},
+        }
+        if duration_secs.is_none() {
+            progress_bar.tick();
+        }
+    }
+    progress_bar.finish_and_clear();
+}
+
+fn add_video_encoder_args(command: &mut Command, crf: u8, config: &Config) {
+    let encoder = &config.encoder;
+    command
+        .arg("-c:v").arg(encoder)
+        .arg("-fps_mode").arg("passthrough")
+        .arg("-g").arg("300");
+    if let Some(ffmpeg_threads) = config.ffmpeg_threads {
+        command.arg("-threads").arg(ffmpeg_threads.to_string());
+    }
+    if is_nvenc_encoder(encoder) {
+        command
+            .arg("-v:b").arg("0").arg("-rc").arg("vbr")
+            .arg("-preset").arg("p7")
+            .arg("-tune").arg("hq")
+            .arg("-temporal-aq").arg("1")
+            .arg("-rc-lookahead").arg("32")
+            .arg("-cq").arg(crf.to_string());
+        if let Some(min_bitrate) = &config.min_bitrate {
+            command.arg("-minrate").arg(min_bitrate);
+        }
+        if encoder == "av1_nvenc" {
+            command.arg("-highbitdepth").arg("1");
+        }
+    } else {
+        // libvpx-vp9 needs zero bitrate to be in constant quality mode
+        command
+            .arg("-b:v").arg("0")
+            .arg("-crf").arg(crf.to_string());
+    }
+    for (option, value) in encoder_opts(config) {
+        command.arg(format!("-{}", option)).arg(value);
+    }
+}
+
+// frames are decoded on the CPU, ffmpeg uploads them to NVENC itself
+fn exec_image_sequence_ffmpeg(pattern: &str, framerate: f64, output_path: impl AsRef<Path>, crf: u8, config: &Config) -> Result<()> {
+    let output_path = output_path.as_ref();
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-y")
+        .arg("-framerate").arg(framerate.to_string())
+        .arg("-pattern_type").arg("glob")
+        .arg("-i").arg(pattern);
+    add_video_encoder_args(&mut command, crf, config);
+    command.arg("-an").arg(output_path);
+    log::debug!("Command: {}", command_line(&command));
+    let _gpu_session = acquire_gpu_session_permit(config);
+    let child = command.spawn().map_err(|e| spawn_error(&command, e, anyhow::Error::from))?;
+    let status = wait_encoder(child, output_path)?;
+    log::debug!("Command status: {:?}", status);
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(Error::ForceCrfFfmpegCommandFailed(status)))
+    }
+}
+
+// spawning fails with NotFound when the external tool isn't installed
+fn spawn_error(command: &Command, e: std::io::Error, other_error: impl FnOnce(std::io::Error) -> anyhow::Error) -> anyhow::Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        anyhow!(Error::MissingDependency(command.get_program().to_string_lossy().to_string()))
+    } else {
+        other_error(e)
+    }
+}
+
+// Copy-paste safe for sh, unlike the Debug output which escapes like Rust strings
+fn command_line(command: &Command) -> String {
+    let envs = command.get_envs().filter_map(|(key, value)| value.map(|value| format!("{}={}", key.to_string_lossy(), shell_quote(value))));
+    let args = std::iter::once(command.get_program()).chain(command.get_args()).map(shell_quote);
+    envs.chain(args).collect::<Vec<_>>().join(" ")
+}
+
+// single quotes keep spaces, quotes and newlines intact, non-UTF-8 bytes need bash's $'\xNN'
+fn shell_quote(arg: impl AsRef<OsStr>) -> String {
+    let bytes = arg.as_ref().as_bytes();
+    if !bytes.is_empty() && bytes.iter().all(|byte| byte.is_ascii_alphanumeric() || b"_-./=:,+@%".contains(byte)) {
+        return String::from_utf8_lossy(bytes).to_string();
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(arg_str) => format!("'{}'", arg_str.replace('\'', "'\\''")),
+        Err(_) => {
+            let escaped: String = bytes.iter().map(|&byte| match byte {
+                b'\'' | b'\\' => format!("\\{}", byte as char),
+                b' '..=b'~' => (byte as char).to_string(),
+                _ => format!("\\x{:02x}", byte),
+            }).collect();
+            format!("$'{}'", escaped)
+        },
+    }
+}
+
+fn set_encode_script(script_path: Option<PathBuf>) {
+    ENCODE_SCRIPT.with(|current| *current.borrow_mut() = script_path);
+}
+
+// named after the source, with a hash of its path as sources in different directories can share a name,
+// and a script left by an earlier run is removed so that it doesn't mix with this one
+fn encode_script_path(scripts_dir: impl AsRef<Path>, video_path: impl AsRef<Path>) -> Result<PathBuf> {
+    let scripts_dir = scripts_dir.as_ref();
+    let video_path = video_path.as_ref();
+    let file_name = video_path.file_name().ok_or(Error::InvalidVideoPath(video_path.to_path_buf()))?;
+    let mut script_name = file_name.to_os_string();
+    script_name.push(format!(".{}.sh", &hash_file_location(video_path)[..8]));
+    let script_path = scripts_dir.join(script_name);
+    match fs::remove_file(&script_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {},
+    }
+    Ok(script_path)
+}
+
+// appended in the order run, a retry follows the failed attempt just like it did here,
+// bash as non-UTF-8 paths are quoted as $'\xNN'
+fn emit_encode_command(command: &Command) -> Result<()> {
+    let Some(script_path) = ENCODE_SCRIPT.with(|current| current.borrow().clone()) else {
+        return Ok(());
+    };
+    let new_script = !script_path.exists();
+    let mut script = fs::OpenOptions::new().create(true).append(true).open(&script_path)?;
+    if new_script {
+        // relative paths resolve against where this run was started
+        writeln!(script, "#!/bin/bash")?;
+        writeln!(script, "cd {}", shell_quote(env::current_dir()?))?;
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+    }
+    writeln!(script, "{}", command_line(command))?;
+    Ok(())
+}
+
+fn set_file_label(file_label: Option<String>) {
+    FILE_LABEL.with(|current| *current.borrow_mut() = file_label);
+}
+
+fn file_label() -> Option<String> {
+    FILE_LABEL.with(|current| current.borrow().clone())
+}
+
+// printed above the progress bars while they are shown, so neither garbles the other
+fn report_line(line: String) {
+    let line = match file_label() {
+        Some(file_label) => format!("{} {}", file_label, line),
+        None => line,
+    };
+    match MULTI_PROGRESS.get() {
+        Some(multi_progress) => {
+            if multi_progress.println(&line).is_err() {
+                println!("{}", line);
+            }
+        },
+        None => println!("{}", line),
+    }
+}
+
+// encode children inherit the affinity of the thread spawning them, unpinned where sched_setaffinity doesn't exist
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cores: &[usize]) {
+    unsafe {
+        let mut cpu_set: libc::cpu_set_t = std::mem::zeroed();
+        for &core in cores {
+            libc::CPU_SET(core, &mut cpu_set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) != 0 {
+            log::warn!("Failed to pin to cores {:?}: {}", cores, std::io::Error::last_os_error());
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(cores: &[usize]) {
+    log::warn!("CPU affinity is not supported on this platform, not pinning to cores {:?}", cores);
+}
+
+// the device is renumbered as 0 in the child, so encoder and decoder options need no index
+fn add_cuda_device_env(command: &mut Command) {
+    if let Some(cuda_device) = CUDA_DEVICE.with(|current| *current.borrow()) {
+        command.env("CUDA_VISIBLE_DEVICES", cuda_device.to_string());
+    }
+}
+
+extern "C" fn handle_interrupt_signal(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// once installed, a signal no longer kills us outright, so partial encodings get cleaned up
+fn install_signal_handlers() {
+    let handler = handle_interrupt_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+fn wait_child(mut child: Child) -> Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            // the child shares our terminal, so Ctrl-C shows up as its signal death
+            if matches!(status.signal(), Some(libc::SIGINT) | Some(libc::SIGTERM)) {
+                return Err(anyhow!(Error::Interrupted));
+            }
+            return Ok(status);
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            log::debug!("Forwarding interrupt to child process {}", child.id());
+            unsafe {
+                libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+            }
+            child.wait()?;
+            return Err(anyhow!(Error::Interrupted));
+        }
+        if INTERRUPT_DEADLINE.get().is_some_and(|deadline| Instant::now() >= *deadline) {
+            log::debug!("Killing child process {} at max runtime", child.id());
+            child.kill()?;
+            child.wait()?;
+            return Err(anyhow!(Error::MaxRuntimeExceeded));
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+// an interrupted or killed encoder leaves a partial output behind
+fn wait_encoder(child: Child, output_path: &Path) -> Result<ExitStatus> {
+    let result = wait_child(child);
+    if result.is_err() && output_path.exists() {
+        remove_encoded_output(output_path)?;
+    }
+    result
+}
+
+// with encoder_subdir, outputs of different encoders sit side by side as save_dir/<encoder>/<name>
+fn encoded_file_save_dir(video_path: impl AsRef<Path>, config: &Config) -> PathBuf {
+    let video_path = video_path.as_ref();
+    let save_dir = save_dir_for_filename(video_path, &config.save_dir, &config.save_dir_overrides);
+    if config.encoder_subdir {
+        save_dir.join(&config.encoder)
+    } else {
+        save_dir
+    }
+}
+
+fn encoded_file_save_path(video_path: impl AsRef<Path>, config: &Config) -> Result<PathBuf> {
+    let video_path = video_path.as_ref();
+
+    // file_stem sometimes treats the last part of the file name as extension
+    // so we impl the way below
+    let video_filename = video_path.file_name().ok_or(Error::InvalidVideoPath(video_path.to_path_buf()))?;
+    let save_dir = encoded_file_save_dir(video_path, config);
+
+    let pre_save_path = save_dir.join(destination_filename(video_filename, config)?);
+    let save_video_filename = rename_for_linux_limit::new_filename(&pre_save_path, Some(&save_dir))?;
+    let save_path = save_dir.join(save_video_filename);
+
+    Ok(save_path)
+}
+
+fn destination_filename(video_filename: impl AsRef<OsStr>, config: &Config) -> Result<PathBuf> {
+    let video_filename = video_filename.as_ref();
+    let renamed_filename = match &config.renamer {
+        Some(renamer) => match renamed_video_filename(video_filename, renamer, &config.output_extension) {
+            Ok(renamed_filename) => Some(PathBuf::from(renamed_filename)),
+            Err(e) if renamer.fallback_on_failure => {
+                log::warn!("Renamer failed for {:?}, falling back to the untransformed name: {}", video_filename, e);
+                None
+            },
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+    let filename = if let Some(renamed_filename) = renamed_filename {
+        renamed_filename
+    } else if config.preserve_source_basename {
+        // keeps the raw OsStr, so non-UTF-8 names survive byte for byte
+        Path::new(video_filename).with_extension(&config.output_extension)
+    } else {
+        let mut iter = video_filename.as_encoded_bytes().rsplitn(1, |&b| b == b'.');
+        let video_slug = iter.next().ok_or(Error::InvalidVideoPath(PathBuf::from(video_filename)))?;
+        let video_slug = String::from_utf8_lossy(video_slug).to_string();
+        PathBuf::from(video_slug).with_extension(&config.output_extension)
+    };
+    Ok(add_output_affixes(&filename, config))
+}
+
+// added before rename_for_linux_limit, so the affixes count toward the limits
+fn add_output_affixes(filename: &Path, config: &Config) -> PathBuf {
+    if config.output_prefix.is_none() && config.output_suffix.is_none() {
+        return filename.to_path_buf();
+    }
+    let mut affixed_filename = OsString::from(config.output_prefix.as_deref().unwrap_or_default());
+    affixed_filename.push(filename.file_stem().unwrap_or_default());
+    affixed_filename.push(config.output_suffix.as_deref().unwrap_or_default());
+    // pushed by hand, as with_extension would cut a stem that contains dots
+    if let Some(extension) = filename.extension() {
+        affixed_filename.push(".");
+        affixed_filename.push(extension);
+    }
+    PathBuf::from(affixed_filename)
+}
+
+// the inverse of add_output_affixes, so that renaming saved files doesn't stack them
+fn strip_output_affixes(filename: &OsStr, config: &Config) -> OsString {
+    // works on bytes, as preserve_source_basename lets non-UTF-8 names through
+    let filename = filename.as_bytes();
+    let (stem, extension) = match filename.iter().rposition(|&b| b == b'.') {
+        Some(dot) => filename.split_at(dot),
+        None => (filename, &[][..]),
+    };
+    let stem = config.output_prefix.as_deref().and_then(|prefix| stem.strip_prefix(prefix.as_bytes())).unwrap_or(stem);
+    let stem = config.output_suffix.as_deref().and_then(|suffix| stem.strip_suffix(suffix.as_bytes())).unwrap_or(stem);
+    OsStr::from_bytes(&[stem, extension].concat()).to_os_string()
+}
+
+// the probe goes to stdin as JSON, so the hook doesn't need to run ffprobe itself
+fn exec_target_policy_hook(video_path: impl AsRef<Path>, probe: &VideoProbe, hook: &HookConfig) -> Result<TargetPolicy> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new(&hook.command);
+    command.args(&hook.args).arg(video_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    log::debug!("Command: {}", command_line(&command));
+    let mut child = command.spawn().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::TargetPolicyHookFailed(format!("{:?}", e)))))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&serde_json::to_vec(probe)?)?;
+    }
+    let output = child.wait_with_output()?;
+    log::debug!("Command output: {:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::TargetPolicyHookFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
+    }
+    let policy: TargetPolicy = serde_json::from_slice(&output.stdout).map_err(|e| Error::TargetPolicyHookFailed(format!("Failed to parse json: {:?}", e)))?;
+    match policy {
+        TargetPolicy::Vmaf(target_vmaf) => report!("Target policy: VMAF {}", target_vmaf),
+        TargetPolicy::Crf(crf) => report!("Target policy: crf {}", crf),
+    }
+    Ok(policy)
+}
+
+// checked up front, so a missing libfdk_aac fails the run instead of every single encode,
+// audio codecs only set in sidecars are left to the encode itself
+fn check_audio_encoders(config: &Config) -> Result<()> {
+    let audio_codecs: Vec<&str> = config.audio_codecs().into_iter().filter(|&audio_codec| audio_codec != "copy").collect();
+    if audio_codecs.is_empty() {
+        return Ok(());
+    }
+
+    let mut command = Command::new("ffmpeg");
+    command.arg("-hide_banner").arg("-encoders");
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, anyhow::Error::from))?;
+    log::debug!("Command status: {:?}", output.status);
+
+    // e.g. " A....D libfdk_aac           Fraunhofer FDK AAC (codec aac)", the name follows the capability flags
+    let stdout_str = String::from_utf8_lossy(&output.stdout);
+    let encoders: HashSet<&str> = stdout_str.lines().filter_map(|line| line.split_whitespace().nth(1)).collect();
+    for audio_codec in audio_codecs {
+        if !encoders.contains(audio_codec) {
+            return Err(anyhow!(Error::AudioEncoderNotAvailable(audio_codec.to_string())));
+        }
+    }
+    Ok(())
+}
+
+// a bare name is looked up on PATH like the shell would, anything with a slash is taken as is
+fn is_executable_command(command: impl AsRef<Path>) -> bool {
+    let command = command.as_ref();
+    let is_executable_file = |path: &Path| fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0);
+    if command.components().count() > 1 {
+        return is_executable_file(command);
+    }
+    env::var_os("PATH").is_some_and(|paths| env::split_paths(&paths).any(|dir| is_executable_file(&dir.join(command))))
+}
+
+fn renamed_video_filename(video_filename: impl AsRef<OsStr>, renamer: &RenamerConfig, output_extension: &str) -> Result<String> {
+    let video_filename = video_filename.as_ref();
+
+    let mut command = Command::new(&renamer.command);
+    command.args(&renamer.args).arg(video_filename);
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => anyhow!(Error::RenamerCommandNotFound(renamer.command.display().to_string())),
+        _ => anyhow!(Error::RenamerCommandFailed(format!("{:?}", e))),
+    })?;
+    log::debug!("Command output: {:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::RenamerCommandFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
+    }
+
+    let stdout_str = String::from_utf8_lossy(&output.stdout);
+    let renamed_filename = stdout_str.lines().next().unwrap_or_default().trim();
+    if renamed_filename.is_empty() {
+        return Err(anyhow!(Error::RenamerCommandFailed(format!("Empty output for {:?}", video_filename))));
+    }
+
+    ensure_output_extension(renamed_filename, output_extension, renamer.strict_extension)
+}
+
+// a different extension is kept as part of the name, e.g. "movie.mp4" -> "movie.mp4.mkv"
+fn ensure_output_extension(filename: &str, output_extension: &str, strict: bool) -> Result<String> {
+    let has_extension = Path::new(filename).extension().is_some_and(|extension| extension.to_string_lossy().eq_ignore_ascii_case(output_extension));
+    if has_extension {
+        Ok(filename.to_string())
+    } else if strict {
+        Err(anyhow!(Error::RenamerInvalidExtension(filename.to_string(), output_extension.to_string())))
+    } else {
+        log::warn!("Renamer output {:?} lacks extension {:?}, appending it", filename, output_extension);
+        Ok(format!("{}.{}", filename, output_extension))
+    }
+}
+
+// "movie.mkv" with the 2nd chapter "Opening" becomes "movie - 02 Opening.mkv"
+fn chapter_file_save_path(save_path: impl AsRef<Path>, index: usize, title: Option<&str>) -> PathBuf {
+    let save_path = save_path.as_ref();
+    let mut filename = save_path.file_stem().unwrap_or_default().to_os_string();
+    filename.push(format!(" - {:02}", index + 1));
+    if let Some(title) = title {
+        filename.push(" ");
+        filename.push(title.replace('/', "_"));
+    }
+    if let Some(extension) = save_path.extension() {
+        filename.push(".");
+        filename.push(extension);
+    }
+    save_path.with_file_name(filename)
+}
+
+// save path, encoding path and, when it's a chapter, its time range
+type Segment = (PathBuf, PathBuf, Option<(f64, f64)>);
+
+// each chapter encodes to "<hash>.<chapter number>.<extension>"
+fn chapter_segments(chapters: &[Chapter], save_path: &Path, encoding_video_dir: &Path, video_location_hash: &str, config: &Config) -> Vec<Segment> {
+    chapters.iter().enumerate().map(|(index, chapter)| {
+        let encoding_video_path = suffixed_encoding_video_path(encoding_video_dir, video_location_hash, index + 1, config);
+        (chapter_file_save_path(save_path, index, chapter.title.as_deref()), encoding_video_path, Some((chapter.start_secs, chapter.end_secs)))
+    }).collect()
+}
+
+// "<hash>.<suffix>.<extension>", as with_extension would take the suffix for the extension and replace it,
+// leaving every suffixed encode of a source on the same path
+fn suffixed_encoding_video_path(encoding_video_dir: impl AsRef<Path>, video_location_hash: &str, suffix: impl std::fmt::Display, config: &Config) -> PathBuf {
+    encoding_video_dir.as_ref().join(format!("{}.{}.{}", video_location_hash, suffix, config.output_extension))
+}
+
+// "movie.mkv" with rendition "1080p" becomes "movie.1080p.mkv"
+fn rendition_file_save_path(save_path: impl AsRef<Path>, rendition_name: &str) -> PathBuf {
+    let save_path = save_path.as_ref();
+    let mut filename = save_path.file_stem().unwrap_or_default().to_os_string();
+    filename.push(".");
+    filename.push(rendition_name);
+    if let Some(extension) = save_path.extension() {
+        filename.push(".");
+        filename.push(extension);
+    }
+    save_path.with_file_name(filename)
+}
+
+fn guess_video_file(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    let guess = mime_guess::from_path(path);
+    let mut iter = guess.iter();
+    iter.any(|mime| mime.type_() == "video")
+}
+
+// taken from the middle of the output, as openings are often black or a logo
+fn exec_preview_ffmpeg(output_path: impl AsRef<Path>, preview: &PreviewConfig, config: &Config) -> Result<PathBuf> {
+    let output_path = output_path.as_ref();
+    let input_path = encoded_output_probe_path(output_path);
+    let preview_path = output_path.with_extension(format!("preview.{}", preview.format.extension()));
+    let start_secs = rough_video_secs(&input_path, config)?.map_or(0.0, |secs| ((secs - preview.duration_secs) / 2.0).max(0.0));
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-y")
+        .arg("-ss").arg(start_secs.to_string())
+        .arg("-t").arg(preview.duration_secs.to_string())
+        .arg("-i").arg(&input_path)
+        .arg("-vf").arg(format!("fps={},scale={}:-2:flags=lanczos", preview.fps, preview.width))
+        .arg("-an")
+        .arg("-loop").arg("0")
+        .arg(&preview_path);
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, anyhow::Error::from))?;
+    log::debug!("Command output: {:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::PreviewFfmpegCommandFailed(output.status)));
+    }
+    Ok(preview_path)
+}
+
+// ffmpeg and ffprobe read an HLS output through its playlist
+fn encoded_output_probe_path(output_path: impl AsRef<Path>) -> PathBuf {
+    let output_path = output_path.as_ref();
+    if output_path.is_dir() {
+        output_path.join(HLS_PLAYLIST_FILENAME)
+    } else {
+        output_path.to_path_buf()
+    }
+}
+
+fn encoded_output_size(output_path: impl AsRef<Path>) -> Result<u64> {
+    let output_path = output_path.as_ref();
+    if !output_path.is_dir() {
+        return Ok(fs::metadata(output_path)?.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(output_path)? {
+        size += entry?.metadata()?.len();
+    }
+    Ok(size)
+}
+
+fn remove_encoded_output(output_path: impl AsRef<Path>) -> Result<()> {
+    let output_path = output_path.as_ref();
+    if output_path.is_dir() {
+        fs::remove_dir_all(output_path)?;
+    } else {
+        fs::remove_file(output_path)?;
+    }
+    Ok(())
+}
+
+// every URI in the playlist, the init segment included, must exist next to it
+fn verify_hls_playlist(hls_dir: impl AsRef<Path>) -> Result<()> {
+    let hls_dir = hls_dir.as_ref();
+    let playlist_path = hls_dir.join(HLS_PLAYLIST_FILENAME);
+    let playlist_str = fs::read_to_string(&playlist_path).map_err(|e| Error::InvalidHlsPlaylist(playlist_path.clone(), format!("{:?}", e)))?;
+
+    let mut segment_count = 0;
+    for line in playlist_str.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+        let uri = match line.strip_prefix("#EXT-X-MAP:URI=") {
+            Some(map_uri) => map_uri.split(',').next().unwrap_or_default().trim_matches('"'),
+            None if line.starts_with('#') => continue,
+            None => {
+                segment_count += 1;
+                line
+            },
+        };
+        if !hls_dir.join(uri).is_file() {
+            return Err(anyhow!(Error::InvalidHlsPlaylist(playlist_path, format!("Missing {}", uri))));
+        }
+    }
+    if segment_count == 0 {
+        return Err(anyhow!(Error::InvalidHlsPlaylist(playlist_path, "No segments".to_string())));
+    }
+    if !playlist_str.contains("#EXT-X-ENDLIST") {
+        return Err(anyhow!(Error::InvalidHlsPlaylist(playlist_path, "No end of list, the encode didn't finish".to_string())));
+    }
+    Ok(())
+}
+
+const MIN_OUTPUT_SECS: f64 = 0.1;
+
+// NVENC sometimes writes outputs with valid dimensions but no frames, which is_valid_video_file lets through
+// an unknown duration passes, as some containers just don't tell
+fn check_output_duration(output_path: impl AsRef<Path>, config: &Config) -> Result<()> {
+    let output_path = output_path.as_ref();
+    match rough_video_secs(output_path, config)? {
+        Some(secs) if secs < MIN_OUTPUT_SECS => Err(anyhow!(Error::ZeroDurationOutput(output_path.to_path_buf()))),
+        _ => Ok(()),
+    }
+}
+
+// 23.976 and 24 are apart by more than this, rounding in the containers is not
+const FRAME_RATE_TOLERANCE: f64 = 0.01;
+
+// fps_mode=passthrough keeps the timestamps, but editing tools go by avg_frame_rate, which can still drift
+fn check_output_frame_rate(output_path: impl AsRef<Path>, source_path: impl AsRef<Path>, config: &Config) -> Result<()> {
+    let output_path = output_path.as_ref();
+    let source_path = source_path.as_ref();
+    let (Some(output_frame_rate), Some(source_frame_rate)) = (avg_frame_rate(output_path, config)?, avg_frame_rate(source_path, config)?) else {
+        return Ok(());
+    };
+    if (output_frame_rate - source_frame_rate).abs() <= FRAME_RATE_TOLERANCE {
+        return Ok(());
+    }
+    if config.verify_fps {
+        return Err(anyhow!(Error::FrameRateMismatch(output_path.to_path_buf(), output_frame_rate, source_frame_rate)));
+    }
+    log::warn!("Output frame rate {:.3} differs from source {:.3}: {:?}", output_frame_rate, source_frame_rate, output_path);
+    report!("Output frame rate {:.3} differs from source {:.3}: {}", output_frame_rate, source_frame_rate, output_path.display());
+    Ok(())
+}
+
+// None when ffprobe doesn't know, e.g. "0/0" for variable frame rate sources
+fn avg_frame_rate(video_path: impl AsRef<Path>, config: &Config) -> Result<Option<f64>> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffprobe");
+    add_input_probe_args(&mut command, config);
+    command
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream=avg_frame_rate")
+        .arg("-of").arg("json")
+        .arg(video_path);
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeProbeVideoFailed(format!("{:?}", e)))))?;
+    log::debug!("Command output: {:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::FfprobeProbeVideoFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
+    }
+
+    let ffprobe_output: FfprobeOutput = serde_json::from_slice(&output.stdout).map_err(|e| Error::FfprobeProbeVideoFailed(format!("Failed to parse json: {:?}", e)))?;
+    Ok(ffprobe_output.streams.first().and_then(|stream| stream.avg_frame_rate.as_deref()).and_then(parse_frame_rate))
+}
+
+fn check_output_size(output_path: impl AsRef<Path>, source_path: impl AsRef<Path>, config: &Config) -> Result<()> {
+    let output_path = output_path.as_ref();
+    let source_path = source_path.as_ref();
+    if config.min_output_bytes.is_none() && config.min_output_ratio.is_none() {
+        return Ok(());
+    }
+    let output_size = encoded_output_size(output_path)?;
+    let min_ratio_bytes = match config.min_output_ratio {
+        Some(min_output_ratio) => (fs::metadata(source_path)?.len() as f64 * min_output_ratio) as u64,
+        None => 0,
+    };
+    let min_size = config.min_output_bytes.unwrap_or(0).max(min_ratio_bytes);
+    if output_size < min_size {
+        return Err(anyhow!(Error::TinyOutput(output_path.to_path_buf(), output_size, min_size)));
+    }
+    Ok(())
+}
+
+// both ffmpeg and ffprobe take these as input options, so they must come before the input
+fn add_input_probe_args(command: &mut Command, config: &Config) {
+    if let Some(probesize) = &config.probesize {
+        command.arg("-probesize").arg(probesize);
+    }
+    if let Some(analyzeduration) = &config.analyzeduration {
+        command.arg("-analyzeduration").arg(analyzeduration);
+    }
+    // as an input option it's the decoder's thread count
+    if let Some(ffmpeg_threads) = config.ffmpeg_threads {
+        command.arg("-threads").arg(ffmpeg_threads.to_string());
+    }
+}
+
+fn is_valid_video_file(video_path: impl AsRef<Path>, config: &Config) -> Result<bool> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffprobe");
+    add_input_probe_args(&mut command, config);
+    command
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream=width,height")
+        .arg("-of").arg("csv=p=0")
+        .arg(video_path);
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeCheckValidVideoFailed(format!("{:?}", e)))))?;
+    log::debug!("Command status: {:?}", output.status);
+
+    if !output.status.success() {
+        return Ok(false);
+    }
+
+    // check w,h
+    let stdout_str = output.stdout;
+    let stdout_str = String::from_utf8_lossy(&stdout_str);
+
+    // let take the first line
+    let stdout_str = stdout_str.split('\n').next().ok_or(Error::FfprobeCheckValidVideoFailed(format!("Failed to get first line: {:?}", stdout_str)))?;
+    let stdout_str = stdout_str.trim();
+    let mut iter = stdout_str.split(',');
+
+    let width_str = iter.next().ok_or(Error::FfprobeCheckValidVideoFailed(format!("Failed to get width,height: {:?}", stdout_str)))?;
+    let height_str = iter.next().ok_or(Error::FfprobeCheckValidVideoFailed(format!("Failed to get width,height: {:?}", stdout_str)))?;
+    let width = width_str.parse::<u32>().map_err(|e| Error::FfprobeCheckValidVideoFailed(format!("Failed to parse width ({}): {:?}", width_str, e)))?;
+    let height = height_str.parse::<u32>().map_err(|e| Error::FfprobeCheckValidVideoFailed(format!("Failed to parse height ({}): {:?}", height_str, e)))?;
+
+    Ok(width > 0 && height > 0)
+}
+
+fn probe_video(video_path: impl AsRef<Path>) -> Result<VideoProbe> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffprobe");
+    command
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream=width,height,codec_name,r_frame_rate,sample_aspect_ratio:stream_side_data=rotation:stream_tags=rotate:format=bit_rate,duration")
+        .arg("-of").arg("json")
+        .arg(video_path);
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeProbeVideoFailed(format!("{:?}", e)))))?;
+    log::debug!("Command output: {:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::FfprobeProbeVideoFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
+    }
+
+    let ffprobe_output: FfprobeOutput = serde_json::from_slice(&output.stdout).map_err(|e| Error::FfprobeProbeVideoFailed(format!("Failed to parse json: {:?}", e)))?;
+    let stream = ffprobe_output.streams.first().ok_or(Error::FfprobeProbeVideoFailed(format!("No video stream: {:?}", ffprobe_output)))?;
+    let width = stream.width.ok_or(Error::FfprobeProbeVideoFailed(format!("No width: {:?}", stream)))?;
+    let height = stream.height.ok_or(Error::FfprobeProbeVideoFailed(format!("No height: {:?}", stream)))?;
+    let codec_name = stream.codec_name.clone();
+    let frame_rate = stream.r_frame_rate.as_deref().and_then(parse_frame_rate);
+    // "0:1" when unknown, which parses to nothing like "N/A"
+    let sample_aspect_ratio = stream.sample_aspect_ratio.as_deref().and_then(parse_aspect_ratio);
+    let rotation = stream.rotation();
+
+    // some containers don't report overall bit_rate, so compute it from size and duration
+    let format = ffprobe_output.format.unwrap_or_default();
+    let duration_secs = format.duration.and_then(|duration| duration.parse::<f64>().ok()).filter(|&duration_secs| duration_secs > 0.0);
+    let bit_rate = match format.bit_rate.and_then(|bit_rate| bit_rate.parse::<u64>().ok()) {
+        Some(bit_rate) => Some(bit_rate),
+        None => {
+            let size = fs::metadata(video_path)?.len();
+            duration_secs.map(|duration_secs| (size as f64 * 8.0 / duration_secs) as u64)
+        },
+    };
+
+    Ok(VideoProbe { width, height, codec_name, duration_secs, bit_rate, frame_rate, sample_aspect_ratio, rotation })
+}
+
+#[derive(Debug)]
+struct Chapter {
+    start_secs: f64,
+    end_secs: f64,
+    title: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FfprobeChapters {
+    #[serde(default)]
+    chapters: Vec<FfprobeChapter>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FfprobeChapter {
+    start_time: String,
+    end_time: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+fn video_chapters(video_path: impl AsRef<Path>) -> Result<Vec<Chapter>> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffprobe");
+    command
+        .arg("-v").arg("error")
+        .arg("-show_chapters")
+        .arg("-of").arg("json")
+        .arg(video_path);
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeShowChaptersFailed(format!("{:?}", e)))))?;
+    log::debug!("Command output: {:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::FfprobeShowChaptersFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
+    }
+
+    let ffprobe_chapters: FfprobeChapters = serde_json::from_slice(&output.stdout).map_err(|e| Error::FfprobeShowChaptersFailed(format!("Failed to parse json: {:?}", e)))?;
+    let mut chapters = Vec::new();
+    for chapter in ffprobe_chapters.chapters {
+        let start_secs = chapter.start_time.parse::<f64>().map_err(|e| Error::FfprobeShowChaptersFailed(format!("Failed to parse start time ({}): {:?}", chapter.start_time, e)))?;
+        let end_secs = chapter.end_time.parse::<f64>().map_err(|e| Error::FfprobeShowChaptersFailed(format!("Failed to parse end time ({}): {:?}", chapter.end_time, e)))?;
+        let title = chapter.tags.get("title").cloned();
+        chapters.push(Chapter { start_secs, end_secs, title });
+    }
+    Ok(chapters)
+}
+
+// includes the crf search for ab-av1, so it compares whole runs rather than raw encoder speed
+fn log_encode_speed(video_path: impl AsRef<Path>, probe: &VideoProbe, elapsed: Duration) {
+    let video_path = video_path.as_ref();
+    let elapsed_secs = elapsed.as_secs_f64();
+    let Some(duration_secs) = probe.duration_secs.filter(|_| elapsed_secs > 0.0) else {
+        report!("Encoded in {:.2} sec: {}", elapsed_secs, video_path.display());
+        return;
+    };
+    let realtime_factor = duration_secs / elapsed_secs;
+    let fps = probe.frame_rate.map(|frame_rate| duration_secs * frame_rate / elapsed_secs);
+    log::info!("Encode speed of {:?}: {:.2}x realtime, {:?} fps, {:.2} sec", video_path, realtime_factor, fps, elapsed_secs);
+    report!("Encoded in {:.2} sec, {:.2}x realtime{}: {}", elapsed_secs, realtime_factor, fps.map(|fps| format!(", {:.1} fps", fps)).unwrap_or_default(), video_path.display());
+}
+
+// tall sources legitimately need more bits, so let VMAF alone decide for them
+fn max_encoded_percent_for_height(source_height: u32, config: &Config) -> Option<u8> {
+    match config.disable_max_percent_above_height {
+        Some(max_height) if source_height > max_height => None,
+        _ => Some(config.max_encoded_percent),
+    }
+}
+
+// never upscale, only scale down sources taller than max_height
+// height is rounded down to even, and width follows aspect ratio with scale=-2
+fn downscale_height(source_height: u32, max_height: Option<u32>) -> Option<u32> {
+    max_height.filter(|&max_height| source_height > max_height).map(|max_height| max_height - max_height % 2)
+}
+
+// full decode of every frame, any error output means the file is damaged somewhere
+fn is_decodable_video_file(video_path: impl AsRef<Path>) -> Result<bool> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-v").arg("error")
+        .arg("-i").arg(video_path)
+        .arg("-f").arg("null").arg("-");
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfmpegDecodeCheckFailed(format!("{:?}", e)))))?;
+    log::debug!("Command status: {:?}", output.status);
+
+    let stderr_str = String::from_utf8_lossy(&output.stderr);
+    if !stderr_str.trim().is_empty() {
+        log::debug!("Decode errors: {}", stderr_str);
+    }
+
+    Ok(output.status.success() && stderr_str.trim().is_empty())
+}
+
+const COMPLEXITY_SAMPLE_SECS: f64 = 60.0;
+// mean scene score treated as the most complex content, talking heads sit around 0.005, action around 0.1
+const FULL_COMPLEXITY_SCENE_SCORE: f64 = 0.1;
+
+// simple content gets the top of the band as it's cheap anyway, complex content the bottom
+fn complexity_target_vmaf(video_path: impl AsRef<Path>, duration_secs: Option<f64>, complexity_vmaf_band: (u8, u8)) -> Result<u8> {
+    let video_path = video_path.as_ref();
+    let low_vmaf = complexity_vmaf_band.0.min(complexity_vmaf_band.1);
+    let high_vmaf = complexity_vmaf_band.0.max(complexity_vmaf_band.1);
+
+    let range = duration_secs.map(|duration_secs| sample_range(duration_secs, COMPLEXITY_SAMPLE_SECS, None));
+    let scene_score = mean_scene_score(video_path, range)?;
+    let complexity = (scene_score / FULL_COMPLEXITY_SCENE_SCORE).clamp(0.0, 1.0);
+    let target_vmaf = (high_vmaf as f64 - (high_vmaf - low_vmaf) as f64 * complexity).round() as u8;
+    report!("Complexity {:.2} (scene score {:.4}), target VMAF {}: {}", complexity, scene_score, target_vmaf, video_path.display());
+    Ok(target_vmaf)
+}
+
+const GRAIN_SAMPLE_SECS: f64 = 10.0;
+
+fn should_denoise(video_path: impl AsRef<Path>, sidecar_denoise: Option<bool>, denoise: &DenoiseConfig, duration_secs: Option<f64>) -> Result<bool> {
+    let video_path = video_path.as_ref();
+    if let Some(sidecar_denoise) = sidecar_denoise {
+        return Ok(sidecar_denoise);
+    }
+    let Some(grain_psnr_below) = denoise.grain_psnr_below else {
+        return Ok(false);
+    };
+    let range = duration_secs.map(|duration_secs| sample_range(duration_secs, GRAIN_SAMPLE_SECS, None));
+    let grain_psnr = denoised_psnr(video_path, range, denoise)?;
+    let grainy = grain_psnr < grain_psnr_below;
+    report!("Grain PSNR {:.2} dB{}: {}", grain_psnr, if grainy { ", denoising" } else { "" }, video_path.display());
+    Ok(grainy)
+}
+
+// PSNR of the sample against its own denoised version, the more grain the filter removes the lower it gets
+fn denoised_psnr(video_path: impl AsRef<Path>, range: Option<(f64, f64)>, denoise: &DenoiseConfig) -> Result<f64> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffmpeg");
+    command.arg("-hide_banner").arg("-nostats");
+    if let Some((start_secs, end_secs)) = range {
+        command.arg("-ss").arg(start_secs.to_string()).arg("-to").arg(end_secs.to_string());
+    }
+    command
+        .arg("-i").arg(video_path)
+        .arg("-an")
+        .arg("-lavfi").arg(format!("[0:v]split[source][denoising];[denoising]{}[denoised];[source][denoised]psnr", denoise.filter()))
+        .arg("-f").arg("null").arg("-");
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfmpegQualityMetricsFailed(format!("{:?}", e)))))?;
+    log::debug!("Command status: {:?}", output.status);
+
+    let stderr_str = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(anyhow!(Error::FfmpegQualityMetricsFailed(format!("Exit with {}: {}", output.status, stderr_str))));
+    }
+    let psnr = stderr_str.lines().filter_map(|line| QualityMetric::Psnr.parse_score(line)).next_back().ok_or(Error::FfmpegQualityMetricsFailed(format!("No psnr score in output: {}", stderr_str)))?;
+    Ok(psnr)
+}
+
+fn mean_scene_score(video_path: impl AsRef<Path>, range: Option<(f64, f64)>) -> Result<f64> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffmpeg");
+    command.arg("-hide_banner").arg("-nostats");
+    if let Some((start_secs, end_secs)) = range {
+        command.arg("-ss").arg(start_secs.to_string()).arg("-to").arg(end_secs.to_string());
+    }
+    command
+        .arg("-i").arg(video_path)
+        .arg("-an")
+        .arg("-vf").arg("select='gte(scene,0)',metadata=print:key=lavfi.scene_score")
+        .arg("-f").arg("null").arg("-");
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfmpegComplexityProbeFailed(format!("{:?}", e)))))?;
+    log::debug!("Command status: {:?}", output.status);
+
+    let stderr_str = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(anyhow!(Error::FfmpegComplexityProbeFailed(format!("Exit with {}: {}", output.status, stderr_str))));
+    }
+
+    // metadata=print logs "lavfi.scene_score=0.012345" per frame
+    let scene_scores: Vec<f64> = stderr_str.lines().filter_map(|line| line.split_once("lavfi.scene_score=")).filter_map(|(_, score)| score.trim().parse().ok()).collect();
+    if scene_scores.is_empty() {
+        return Err(anyhow!(Error::FfmpegComplexityProbeFailed(format!("No scene score in output: {}", stderr_str))));
+    }
+    Ok(scene_scores.iter().sum::<f64>() / scene_scores.len() as f64)
+}
+
+// one line per error, a few are normal for broadcast captures, thousands mean a broken rip
+fn count_decode_errors(video_path: impl AsRef<Path>) -> Result<usize> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-v").arg("error")
+        .arg("-i").arg(video_path)
+        .arg("-f").arg("null").arg("-");
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfmpegDecodeCheckFailed(format!("{:?}", e)))))?;
+    log::debug!("Command status: {:?}", output.status);
+
+    let stderr_str = String::from_utf8_lossy(&output.stderr);
+    let decode_errors = stderr_str.lines().filter(|line| !line.trim().is_empty()).count();
+    log::debug!("Decode errors of {:?}: {}", video_path, decode_errors);
+    Ok(decode_errors)
+}
+
+// VMAF of distorted against reference, reference_range cuts the reference to where the distorted sample came from
+fn measure_vmaf(distorted_path: impl AsRef<Path>, reference_path: impl AsRef<Path>, reference_range: Option<(f64, f64)>) -> Result<f64> {
+    let scores = measure_quality_metrics(distorted_path, reference_path, reference_range, &[QualityMetric::Vmaf])?;
+    Ok(scores[0])
+}
+
+// one decode of both inputs, split into a filter per metric, scores are in the order of metrics
+fn measure_quality_metrics(distorted_path: impl AsRef<Path>, reference_path: impl AsRef<Path>, reference_range: Option<(f64, f64)>, metrics: &[QualityMetric]) -> Result<Vec<f64>> {
+    let distorted_path = distorted_path.as_ref();
+    let reference_path = reference_path.as_ref();
+
+    // distorted may be downscaled, so compare at the reference resolution
+    let mut lavfi = "[0:v][1:v]scale2ref=flags=bicubic[distorted][reference]".to_string();
+    lavfi.push_str(&format!(";[distorted]split={}{}", metrics.len(), (0..metrics.len()).map(|index| format!("[distorted{}]", index)).collect::<String>()));
+    lavfi.push_str(&format!(";[reference]split={}{}", metrics.len(), (0..metrics.len()).map(|index| format!("[reference{}]", index)).collect::<String>()));
+    for (index, metric) in metrics.iter().enumerate() {
+        lavfi.push_str(&format!(";[distorted{}][reference{}]{}", index, index, metric.filter()));
+    }
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-hide_banner").arg("-nostats")
+        .arg("-i").arg(distorted_path);
+    if let Some((start_secs, end_secs)) = reference_range {
+        command.arg("-ss").arg(start_secs.to_string()).arg("-to").arg(end_secs.to_string());
+    }
+    command
+        .arg("-i").arg(reference_path)
+        .arg("-lavfi").arg(lavfi)
+        .arg("-f").arg("null").arg("-");
+    log::debug!("Command: {}", command_line(&command));
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfmpegQualityMetricsFailed(format!("{:?}", e)))))?;
+    log::debug!("Command status: {:?}", output.status);
+
+    let stderr_str = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(anyhow!(Error::FfmpegQualityMetricsFailed(format!("Exit with {}: {}", output.status, stderr_str))));
+    }
+
+    let mut scores = Vec::new();
+    for metric in metrics {
+        let score = stderr_str.lines().filter_map(|line| metric.parse_score(line)).next_back().ok_or(Error::FfmpegQualityMetricsFailed(format!("No {} score in output: {}", metric.name(), stderr_str)))?;
+        scores.push(score);
+    }
+    Ok(scores)
+}
+
+// relative to the video_dir it was found under, tags are UTF-8 only, so a lossy path is flagged with SOURCE_PATH_LOSSY
+fn source_path_metadata(video_path: impl AsRef<Path>, video_dirs: &[PathBuf]) -> Vec<(String, String)> {
+    let video_path = video_path.as_ref();
+    let source_path = video_dirs.iter().find_map(|video_dir| video_path.strip_prefix(video_dir).ok()).unwrap_or(video_path);
+    let mut metadata = Vec::new();
+    match source_path.to_str() {
+        Some(source_path_str) => metadata.push(("SOURCE_PATH".to_string(), source_path_str.to_string())),
+        None => {
+            log::warn!("Source path is not UTF-8, recording it lossy: {:?}", source_path);
+            metadata.push(("SOURCE_PATH".to_string(), source_path.to_string_lossy().to_string()));
+            metadata.push(("SOURCE_PATH_LOSSY".to_string(), "1".to_string()));
+        },
+    }
+    metadata
+}
+
+// 0 without rotation metadata, disc images never carry any
+fn video_rotation(video_path: impl AsRef<Path>, config: &Config) -> Result<u32> {
+    let video_path = video_path.as_ref();
+    if config.expand_disc_images && is_disc_image(video_path) {
+        return Ok(0);
+    }
 
     let mut command = Command::new("ffprobe");
+    add_input_probe_args(&mut command, config);
     command
         .arg("-v").arg("error")
         .arg("-select_streams").arg("v:0")
-        .arg("-show_entries").arg("stream=width,height")
-        .arg("-of").arg("csv=p=0")
+        .arg("-show_entries").arg("stream_side_data=rotation:stream_tags=rotate")
+        .arg("-of").arg("json")
         .arg(video_path);
-    log::debug!("Command: {:?}", command);
-    let output = command.output().map_err(|e| Error::FfprobeCheckValidVideoFailed(format!("{:?}", e)))?;
-    log::debug!("Command status: {:?}", output.status);
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeProbeVideoFailed(format!("{:?}", e)))))?;
+    log::debug!("Command output: {:?}", output);
 
     if !output.status.success() {
-        return Ok(false);
+        return Err(anyhow!(Error::FfprobeProbeVideoFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
     }
 
-    // check w,h
-    let stdout_str = output.stdout;
-    let stdout_str = String::from_utf8_lossy(&stdout_str);
+    let ffprobe_output: FfprobeOutput = serde_json::from_slice(&output.stdout).map_err(|e| Error::FfprobeProbeVideoFailed(format!("Failed to parse json: {:?}", e)))?;
+    Ok(ffprobe_output.streams.first().map_or(0, |stream| stream.rotation()))
+}
 
-    // let take the first line
-    let stdout_str = stdout_str.split('\n').next().ok_or(Error::FfprobeCheckValidVideoFailed(format!("Failed to get first line: {:?}", stdout_str)))?;
-    let stdout_str = stdout_str.trim();
-    let mut iter = stdout_str.split(',');
+// None unless there are several programs to choose from, a single one is what ffmpeg picks anyway
+fn selected_program(video_path: impl AsRef<Path>, selection: ProgramSelection, config: &Config) -> Result<Option<u32>> {
+    let video_path = video_path.as_ref();
+    if config.expand_disc_images && is_disc_image(video_path) {
+        return Ok(None);
+    }
 
-    let width_str = iter.next().ok_or(Error::FfprobeCheckValidVideoFailed(format!("Failed to get width,height: {:?}", stdout_str)))?;
-    let height_str = iter.next().ok_or(Error::FfprobeCheckValidVideoFailed(format!("Failed to get width,height: {:?}", stdout_str)))?;
-    let width = width_str.parse::<u32>().map_err(|e| Error::FfprobeCheckValidVideoFailed(format!("Failed to parse width ({}): {:?}", width_str, e)))?;
-    let height = height_str.parse::<u32>().map_err(|e| Error::FfprobeCheckValidVideoFailed(format!("Failed to parse height ({}): {:?}", height_str, e)))?;
+    let mut command = Command::new("ffprobe");
+    add_input_probe_args(&mut command, config);
+    command
+        .arg("-v").arg("error")
+        .arg("-show_programs")
+        .arg("-show_entries").arg("program=program_id:program_stream=width,height")
+        .arg("-of").arg("json")
+        .arg(video_path);
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeProbeVideoFailed(format!("{:?}", e)))))?;
+    log::debug!("Command output: {:?}", output);
 
-    Ok(width > 0 && height > 0)
+    if !output.status.success() {
+        return Err(anyhow!(Error::FfprobeProbeVideoFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
+    }
+
+    let ffprobe_programs: FfprobePrograms = serde_json::from_slice(&output.stdout).map_err(|e| Error::FfprobeProbeVideoFailed(format!("Failed to parse json: {:?}", e)))?;
+    let programs = ffprobe_programs.programs;
+    if programs.len() < 2 {
+        return Ok(None);
+    }
+    let program_id = match selection {
+        ProgramSelection::First => programs[0].program_id,
+        ProgramSelection::Largest => {
+            let pixels = |program: &FfprobeProgram| program.streams.iter().map(|stream| stream.width.unwrap_or(0) as u64 * stream.height.unwrap_or(0) as u64).max().unwrap_or(0);
+            // max_by_key keeps the last of equals, the first one is the safer guess
+            programs.iter().rev().max_by_key(|program| pixels(program)).expect("programs must not be empty").program_id
+        },
+        ProgramSelection::Id(id) => programs.iter().find(|program| program.program_id == id).map(|program| program.program_id).ok_or(Error::ProgramNotFound(video_path.to_path_buf(), id))?,
+    };
+    log::debug!("Selected program {} of {}: {}", program_id, programs.len(), video_path.display());
+    Ok(Some(program_id))
+}
+
+fn check_stream_index(video_path: impl AsRef<Path>, index: usize, codec_type: &str, config: &Config) -> Result<()> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffprobe");
+    add_input_probe_args(&mut command, config);
+    command
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg(index.to_string())
+        .arg("-show_entries").arg("stream=codec_type")
+        .arg("-of").arg("csv=p=0")
+        .arg(video_path);
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeProbeVideoFailed(format!("{:?}", e)))))?;
+    log::debug!("Command output: {:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow!(Error::FfprobeProbeVideoFailed(format!("Exit with {}: {}", output.status, String::from_utf8_lossy(&output.stderr)))));
+    }
+    // nothing is printed for an index past the last stream
+    if String::from_utf8_lossy(&output.stdout).trim() != codec_type {
+        return Err(anyhow!(Error::StreamNotFound(video_path.to_path_buf(), index, codec_type.to_string())));
+    }
+    Ok(())
 }
 
-fn rough_video_secs(video_path: impl AsRef<Path>) -> Result<f64> {
+// None means the duration is unknown, e.g. ffprobe says "N/A" for some streams and images
+fn rough_video_secs(video_path: impl AsRef<Path>, config: &Config) -> Result<Option<f64>> {
     let video_path = video_path.as_ref();
 
     let mut command = Command::new("ffprobe");
+    add_input_probe_args(&mut command, config);
     command
         .arg("-v").arg("quiet")
         .arg("-show_entries").arg("format=duration")
         .arg("-of").arg("csv=p=0")
         .arg(video_path);
-    log::debug!("Command: {:?}", command);
-    let output = command.output().map_err(|e| Error::FfprobeShowDurationFailed(format!("{:?}", e)))?;
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeShowDurationFailed(format!("{:?}", e)))))?;
     log::debug!("Command output: {:?}", output);
 
-    let stdout_str = output.stdout;
-    let secs_str = String::from_utf8_lossy(&stdout_str);
+    match parse_format_duration(&output.stdout)? {
+        Some(secs) => Ok(Some(secs)),
+        None => {
+            log::debug!("Format duration unavailable for {:?}, falling back to the video stream", video_path);
+            stream_video_secs(video_path, config)
+        }
+    }
+}
+
+// "1234.5\n" -> 1234.5, "" or "N/A" -> None
+fn parse_format_duration(stdout: &[u8]) -> Result<Option<f64>> {
+    let secs_str = String::from_utf8_lossy(stdout);
     let secs_str = secs_str.trim();
+    if secs_str.is_empty() || secs_str == "N/A" {
+        return Ok(None);
+    }
     let secs = secs_str.parse::<f64>().map_err(|e| Error::ParseDurationSecondsFailed(format!("Failed parsed \"{}\": {:?}", secs_str, e)))?;
 
-    Ok(secs)
+    Ok(Some(secs))
+}
+
+// stream duration, or frame count divided by frame rate
+fn stream_video_secs(video_path: impl AsRef<Path>, config: &Config) -> Result<Option<f64>> {
+    let video_path = video_path.as_ref();
+
+    let mut command = Command::new("ffprobe");
+    add_input_probe_args(&mut command, config);
+    command
+        .arg("-v").arg("quiet")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream=duration,nb_frames,r_frame_rate")
+        .arg("-of").arg("json")
+        .arg(video_path);
+    log::debug!("Command: {}", command_line(&command));
+    let _permit = acquire_probe_permit();
+    let output = command.output().map_err(|e| spawn_error(&command, e, |e| anyhow!(Error::FfprobeShowDurationFailed(format!("{:?}", e)))))?;
+    log::debug!("Command output: {:?}", output);
+
+    parse_stream_duration(&output.stdout)
+}
+
+fn parse_stream_duration(stdout: &[u8]) -> Result<Option<f64>> {
+    let ffprobe_output: FfprobeOutput = serde_json::from_slice(stdout).map_err(|e| Error::FfprobeShowDurationFailed(format!("Failed to parse json: {:?}", e)))?;
+    let Some(stream) = ffprobe_output.streams.first() else {
+        return Ok(None);
+    };
+
+    if let Some(secs) = stream.duration.as_deref().and_then(|duration| duration.parse::<f64>().ok()) {
+        return Ok(Some(secs));
+    }
+
+    let frames = stream.nb_frames.as_deref().and_then(|nb_frames| nb_frames.parse::<f64>().ok());
+    let frame_rate = stream.r_frame_rate.as_deref().and_then(parse_frame_rate);
+    match (frames, frame_rate) {
+        (Some(frames), Some(frame_rate)) => Ok(Some(frames / frame_rate)),
+        _ => Ok(None),
+    }
+}
+
+// "32:27" -> 1.185, "0:1" -> None
+fn parse_aspect_ratio(aspect_ratio_str: &str) -> Option<f64> {
+    let (width, height) = aspect_ratio_str.split_once(':')?;
+    let width = width.trim().parse::<f64>().ok()?;
+    let height = height.trim().parse::<f64>().ok()?;
+    if width > 0.0 && height > 0.0 {
+        Some(width / height)
+    } else {
+        None
+    }
+}
+
+// "30000/1001" -> 29.97, "0/0" -> None
+fn parse_frame_rate(frame_rate_str: &str) -> Option<f64> {
+    let (num, den) = frame_rate_str.split_once('/')?;
+    let num = num.trim().parse::<f64>().ok()?;
+    let den = den.trim().parse::<f64>().ok()?;
+    if num > 0.0 && den > 0.0 {
+        Some(num / den)
+    } else {
+        None
+    }
+}
+
+// write to a sibling temp file and rename, so readers never see partial JSON
+fn write_status_file(path: impl AsRef<Path>, status: &RunStatus) -> Result<()> {
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut tmp_file = fs::File::create(&tmp_path)?;
+    tmp_file.write_all(&serde_json::to_vec_pretty(status)?)?;
+    // synced before the rename, so a crash leaves either the old or the new status, never an empty file
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// "6h", "90m", "1h30m", "45s" or plain seconds
+fn parse_duration(duration_str: &str) -> Result<Duration, String> {
+    let duration_str = duration_str.trim();
+    if let Ok(secs) = duration_str.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let mut secs = 0;
+    let mut number = String::new();
+    for c in duration_str.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit_secs = match c {
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(format!("Invalid duration unit {:?} in {:?}", c, duration_str)),
+        };
+        let value = number.parse::<u64>().map_err(|_| format!("Missing number before {:?} in {:?}", c, duration_str))?;
+        secs += value * unit_secs;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(format!("Missing unit after {:?} in {:?}", number, duration_str));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
 }
 
 fn hash_file_location(file_path: impl AsRef<Path>) -> String {
@@ -454,6 +6103,41 @@ fn hash_file_location(file_path: impl AsRef<Path>) -> String {
     hash.to_hex().to_string()
 }
 
+fn file_hash(file_path: impl AsRef<Path>) -> Result<String> {
+    let file_path = file_path.as_ref();
+    let mut hasher = Hasher::new();
+    hasher.update_reader(fs::File::open(file_path)?)?;
+    let hash = hasher.finalize();
+    Ok(hash.to_hex().to_string())
+}
+
+const FINGERPRINT_CHUNK_BYTES: u64 = 4 * 1024 * 1024;
+
+// Hashes the size plus the first and last few MB, cheap even for huge files
+// and still robust enough to catch renamed duplicates
+fn content_fingerprint(file_path: impl AsRef<Path>) -> Result<String> {
+    let file_path = file_path.as_ref();
+    let mut file = fs::File::open(file_path)?;
+    let size = file.metadata()?.len();
+
+    let mut hasher = Hasher::new();
+    hasher.update(&size.to_le_bytes());
+    if size <= FINGERPRINT_CHUNK_BYTES * 2 {
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        hasher.update(&buf);
+    } else {
+        let mut buf = vec![0; FINGERPRINT_CHUNK_BYTES as usize];
+        file.read_exact(&mut buf)?;
+        hasher.update(&buf);
+        file.seek(SeekFrom::End(-(FINGERPRINT_CHUNK_BYTES as i64)))?;
+        file.read_exact(&mut buf)?;
+        hasher.update(&buf);
+    }
+    let hash = hasher.finalize();
+    Ok(hash.to_hex().to_string())
+}
+
 fn save_dir_for_filename(filename: impl AsRef<OsStr>, default_save_dir: impl AsRef<Path>, save_dir_overrides: &HashMap<String, PathBuf>) -> PathBuf {
     let filename = filename.as_ref();
     let lower_case_filename = filename.to_string_lossy().to_lowercase();
@@ -468,3 +6152,506 @@ fn save_dir_for_filename(filename: impl AsRef<OsStr>, default_save_dir: impl AsR
     save_dir.to_path_buf()
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of<T: std::fmt::Debug>(result: Result<T>) -> Error {
+        let e = result.expect_err("must fail");
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn ensure_output_extension_keeps_matching_extension() {
+        for strict in [false, true] {
+            assert_eq!(ensure_output_extension("movie.mkv", "mkv", strict).unwrap(), "movie.mkv");
+            assert_eq!(ensure_output_extension("MOVIE.MKV", "mkv", strict).unwrap(), "MOVIE.MKV");
+        }
+    }
+
+    #[test]
+    fn ensure_output_extension_appends_when_not_strict() {
+        assert_eq!(ensure_output_extension("movie", "mkv", false).unwrap(), "movie.mkv");
+        assert_eq!(ensure_output_extension("movie.mp4", "mkv", false).unwrap(), "movie.mp4.mkv");
+    }
+
+    #[test]
+    fn ensure_output_extension_rejects_when_strict() {
+        for filename in ["movie", "movie.mp4"] {
+            assert!(matches!(error_of(ensure_output_extension(filename, "mkv", true)), Error::RenamerInvalidExtension(name, extension) if name == filename && extension == "mkv"));
+        }
+    }
+
+    // a fresh directory per test, as tests run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("batch-av1-test-{}-{}", std::process::id(), name));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // the renamer prints its argument with the extension swapped by a sed expression
+    fn sed_renamer(expression: &str, strict_extension: bool) -> RenamerConfig {
+        RenamerConfig {
+            command: PathBuf::from("sh"),
+            args: vec!["-c".to_string(), format!("printf '%s\\n' \"$0\" | sed '{}'", expression)],
+            strict_extension,
+            fallback_on_failure: false,
+        }
+    }
+
+    #[test]
+    fn renamed_video_filename_handles_renamer_extensions() {
+        assert_eq!(renamed_video_filename("movie.avi", &sed_renamer("s/avi$/mkv/", false), "mkv").unwrap(), "movie.mkv");
+        assert_eq!(renamed_video_filename("movie.avi", &sed_renamer("s/\\.avi$//", false), "mkv").unwrap(), "movie.mkv");
+        assert_eq!(renamed_video_filename("movie.avi", &sed_renamer("s/avi$/mp4/", false), "mkv").unwrap(), "movie.mp4.mkv");
+        assert!(matches!(error_of(renamed_video_filename("movie.avi", &sed_renamer("s/\\.avi$//", true), "mkv")), Error::RenamerInvalidExtension(..)));
+        assert!(matches!(error_of(renamed_video_filename("movie.avi", &sed_renamer("s/avi$/mp4/", true), "mkv")), Error::RenamerInvalidExtension(..)));
+    }
+
+    #[test]
+    fn readonly_save_rejects_writes() {
+        let dir = test_dir("readonly-save");
+        let config = Config { readonly_save: true, ..Config::default() };
+        let encoded_path = dir.join("encoded.mkv");
+        fs::write(&encoded_path, "new").unwrap();
+        let save_path = dir.join("saved.mkv");
+        fs::write(&save_path, "old").unwrap();
+        let replacing_path = dir.join(".saved.mkv.replacing");
+        fs::write(&replacing_path, "leftover").unwrap();
+
+        let new_dir = dir.join("new");
+        assert!(matches!(error_of(create_save_dir(&new_dir, &config)), Error::ReadonlySaveDir(path) if path == new_dir));
+        assert!(!new_dir.exists());
+
+        let new_path = dir.join("new.mkv");
+        assert!(matches!(error_of(rename_into_save_dir(&encoded_path, &new_path, &config)), Error::ReadonlySaveDir(path) if path == new_path));
+        assert!(!new_path.exists());
+
+        assert!(matches!(error_of(replace_in_save_dir(&encoded_path, &save_path, &config)), Error::ReadonlySaveDir(path) if path == save_path));
+        assert_eq!(fs::read_to_string(&save_path).unwrap(), "old");
+        assert_eq!(fs::read_to_string(&replacing_path).unwrap(), "leftover");
+        assert_eq!(fs::read_to_string(&encoded_path).unwrap(), "new");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_format_duration_handles_missing_values() {
+        assert_eq!(parse_format_duration(b"").unwrap(), None);
+        assert_eq!(parse_format_duration(b"\n").unwrap(), None);
+        assert_eq!(parse_format_duration(b"N/A\n").unwrap(), None);
+        assert_eq!(parse_format_duration(b"1234.5\n").unwrap(), Some(1234.5));
+        assert!(matches!(error_of(parse_format_duration(b"abc\n")), Error::ParseDurationSecondsFailed(_)));
+    }
+
+    #[test]
+    fn parse_stream_duration_falls_back_to_frame_count() {
+        assert_eq!(parse_stream_duration(br#"{"streams": [{"duration": "12.5"}]}"#).unwrap(), Some(12.5));
+        assert_eq!(parse_stream_duration(br#"{"streams": [{"duration": "N/A", "nb_frames": "300", "r_frame_rate": "30/1"}]}"#).unwrap(), Some(10.0));
+        assert_eq!(parse_stream_duration(br#"{"streams": [{"nb_frames": "300", "r_frame_rate": "0/0"}]}"#).unwrap(), None);
+        assert_eq!(parse_stream_duration(br#"{"streams": [{"nb_frames": "300"}]}"#).unwrap(), None);
+        assert_eq!(parse_stream_duration(br#"{"streams": []}"#).unwrap(), None);
+        assert_eq!(parse_stream_duration(br#"{}"#).unwrap(), None);
+        assert!(matches!(error_of(parse_stream_duration(b"")), Error::FfprobeShowDurationFailed(_)));
+    }
+
+    // set in the child process an interrupt test re-runs itself in, to the test's dir
+    const INTERRUPT_TEST_DIR_ENV: &str = "BATCH_AV1_INTERRUPT_TEST_DIR";
+
+    fn interrupt_test_config(dir: &Path) -> Config {
+        Config { tmp_dir: dir.join("tmp"), save_dir: dir.join("save"), ..Config::default() }
+    }
+
+    // Runs the test again in a child process with stub ffprobe, ab-av1 and ffmpeg first on its PATH,
+    // and sends it SIGTERM once the stub ffmpeg has written a partial output, so that neither the
+    // PATH nor the signal reaches the other tests of this process
+    fn run_interrupted_child(test_name: &str, dir: &Path) {
+        let bin_dir = dir.join("bin");
+        fs::create_dir(&bin_dir).unwrap();
+        let ready_path = dir.join("ready");
+        let stubs = [
+            ("ffprobe", "case \"$*\" in\n  *'stream=width,height -of csv'*) echo 1920,1080 ;;\n  *'format=duration -of csv'*) echo 60 ;;\n  *) echo '{\"streams\": [{\"width\": 1920, \"height\": 1080, \"codec_name\": \"h264\", \"r_frame_rate\": \"24/1\"}], \"format\": {\"duration\": \"60\", \"bit_rate\": \"8000000\"}}' ;;\nesac".to_string()),
+            // like the real one, ab-av1 leaves the encoding to ffmpeg
+            ("ab-av1", "exec ffmpeg \"$@\"".to_string()),
+            ("ffmpeg", format!("while [ $# -gt 0 ]; do\n  case \"$1\" in\n    --temp-dir) touch \"$2/sample.mkv\" ;;\n    -o) printf partial > \"$2\" ;;\n  esac\n  shift\ndone\ntouch {}\nexec sleep 30", shell_quote(&ready_path))),
+        ];
+        for (name, script) in stubs {
+            let stub_path = bin_dir.join(name);
+            fs::write(&stub_path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            fs::set_permissions(&stub_path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let mut child = Command::new(env::current_exe().unwrap())
+            .arg("--exact").arg(format!("tests::{}", test_name)).arg("--nocapture")
+            .env(INTERRUPT_TEST_DIR_ENV, dir)
+            .env("PATH", format!("{}:{}", bin_dir.display(), env::var("PATH").unwrap_or_default()))
+            .stdout(Stdio::piped()).stderr(Stdio::piped())
+            .spawn().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !ready_path.exists() {
+            if Instant::now() >= deadline || child.try_wait().unwrap().is_some() {
+                child.kill().ok();
+                let output = child.wait_with_output().unwrap();
+                panic!("Stub ffmpeg never started: {}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        unsafe {
+            libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    }
+
+    fn files_under(dir: &Path) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        entries.flat_map(|entry| {
+            let path = entry.unwrap().path();
+            if path.is_dir() { files_under(&path) } else { vec![path] }
+        }).collect()
+    }
+
+    #[test]
+    fn interrupt_removes_partial_output_and_samples() {
+        if let Ok(dir) = env::var(INTERRUPT_TEST_DIR_ENV) {
+            let dir = PathBuf::from(dir);
+            install_signal_handlers();
+            let result = exec_ab_av1(dir.join("source.mkv"), dir.join("encoded.mkv"), 95, None, None, false, "info", &interrupt_test_config(&dir));
+            assert!(matches!(error_of(result), Error::Interrupted));
+            return;
+        }
+
+        let dir = test_dir("interrupt");
+        run_interrupted_child("interrupt_removes_partial_output_and_samples", &dir);
+        assert!(!dir.join("encoded.mkv").exists());
+        assert_eq!(files_under(&dir.join("tmp")), Vec::<PathBuf>::new());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_video_is_not_saved() {
+        if let Ok(dir) = env::var(INTERRUPT_TEST_DIR_ENV) {
+            let dir = PathBuf::from(dir);
+            install_signal_handlers();
+            let config = interrupt_test_config(&dir);
+            let opts = AllOpts::try_parse_from(["all", &dir.join("videos").to_string_lossy(), "95"]).unwrap();
+            let ctx = RunContext {
+                opts: &opts,
+                config: &config,
+                inherited_log_level: "info".to_string(),
+                dedupe_index: DedupeIndex::build(&[]).unwrap(),
+                save_dir_index: None,
+                catalog: None,
+                multiparts: HashMap::new(),
+                save_sender: None,
+            };
+            let result = process_video(&dir.join("videos").join("source.mkv"), &[], &ctx);
+            assert!(matches!(error_of(result), Error::Interrupted));
+            return;
+        }
+
+        let dir = test_dir("interrupted-video");
+        fs::create_dir(dir.join("videos")).unwrap();
+        fs::write(dir.join("videos").join("source.mkv"), "source").unwrap();
+        run_interrupted_child("interrupted_video_is_not_saved", &dir);
+        assert_eq!(fs::read_to_string(dir.join("videos").join("source.mkv")).unwrap(), "source");
+        assert_eq!(files_under(&dir.join("save")), Vec::<PathBuf>::new());
+        assert_eq!(files_under(&dir.join("tmp")), Vec::<PathBuf>::new());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn destination_filename_preserves_source_basename() {
+        let config = Config { preserve_source_basename: true, ..Config::default() };
+        let cases = [
+            ("Movie.Name.MKV", "Movie.Name.mkv"),
+            ("a.b.c.mkv", "a.b.c.mkv"),
+            (".hidden.avi", ".hidden.mkv"),
+            ("..movie.avi", "..movie.mkv"),
+            (".hidden", ".hidden.mkv"),
+            ("it's a \"movie\" .avi", "it's a \"movie\" .mkv"),
+        ];
+        for (video_filename, expected) in cases {
+            assert_eq!(destination_filename(video_filename, &config).unwrap(), PathBuf::from(expected), "{}", video_filename);
+        }
+
+        let video_filename = OsStr::from_bytes(b"caf\xe9.avi");
+        assert_eq!(destination_filename(video_filename, &config).unwrap().as_os_str().as_bytes(), b"caf\xe9.mkv");
+    }
+
+    #[test]
+    fn output_affixes_round_trip_with_preserve_source_basename() {
+        let config = Config {
+            preserve_source_basename: true,
+            output_prefix: Some("[av1] ".to_string()),
+            output_suffix: Some(" (encoded)".to_string()),
+            ..Config::default()
+        };
+        let cases: [(&[u8], &[u8]); 4] = [
+            (b"a.b.c.avi", b"[av1] a.b.c (encoded).mkv"),
+            (b".hidden.avi", b"[av1] .hidden (encoded).mkv"),
+            (b"it's \"quoted\".avi", b"[av1] it's \"quoted\" (encoded).mkv"),
+            (b"caf\xe9.avi", b"[av1] caf\xe9 (encoded).mkv"),
+        ];
+        for (video_filename, expected) in cases {
+            let saved_filename = destination_filename(OsStr::from_bytes(video_filename), &config).unwrap();
+            assert_eq!(saved_filename.as_os_str().as_bytes(), expected);
+            // what the rename command does with a saved file, which must not stack the affixes
+            let renamed_filename = destination_filename(strip_output_affixes(saved_filename.as_os_str(), &config), &config).unwrap();
+            assert_eq!(renamed_filename, saved_filename);
+        }
+    }
+
+    #[test]
+    fn shell_quote_round_trips_through_bash() {
+        let args: [&[u8]; 9] = [
+            b"plain-name.mkv",
+            b"it's",
+            b"$HOME",
+            b"`id`",
+            b"two\nlines",
+            b"-leading-dash",
+            b"",
+            b"caf\xe9 it's \\ $x",
+            b"\xff\n'",
+        ];
+        for arg in args {
+            let quoted = shell_quote(OsStr::from_bytes(arg));
+            let output = Command::new("bash").arg("-c").arg(format!("printf %s {}", quoted)).output().unwrap();
+            assert!(output.status.success(), "{}", quoted);
+            assert_eq!(output.stdout, arg, "{}", quoted);
+        }
+    }
+
+    fn rotated_probe(ffprobe_json: &str) -> VideoProbe {
+        let ffprobe_output: FfprobeOutput = serde_json::from_str(ffprobe_json).unwrap();
+        let stream = ffprobe_output.streams.first().unwrap();
+        VideoProbe {
+            width: stream.width.unwrap(),
+            height: stream.height.unwrap(),
+            codec_name: None,
+            duration_secs: None,
+            bit_rate: None,
+            frame_rate: None,
+            sample_aspect_ratio: None,
+            rotation: stream.rotation(),
+        }
+    }
+
+    #[test]
+    fn rotation_reads_display_matrix_and_rotate_tag() {
+        let preserve = Config { rotation_mode: RotationMode::Preserve, ..Config::default() };
+        let bake = Config { rotation_mode: RotationMode::Bake, ..Config::default() };
+
+        // the display matrix turns counterclockwise, so -90 is what phones write along with rotate=90
+        let probe = rotated_probe(r#"{"streams": [{"width": 1920, "height": 1080, "side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}]}]}"#);
+        assert_eq!(probe.rotation, 90);
+        assert_eq!(probe.filter_height(&preserve), 1080);
+        assert_eq!(probe.filter_height(&bake), 1920);
+        assert_eq!(probe.display_aspect_ratio(), 1080.0 / 1920.0);
+
+        let probe = rotated_probe(r#"{"streams": [{"width": 1920, "height": 1080, "side_data_list": [{"side_data_type": "Display Matrix", "rotation": 90}]}]}"#);
+        assert_eq!(probe.rotation, 270);
+        assert_eq!(probe.filter_height(&bake), 1920);
+        assert_eq!(probe.display_aspect_ratio(), 1080.0 / 1920.0);
+
+        // the display matrix wins over the legacy tag
+        let probe = rotated_probe(r#"{"streams": [{"width": 1920, "height": 1080, "side_data_list": [{"rotation": 180}], "tags": {"rotate": "90"}}]}"#);
+        assert_eq!(probe.rotation, 180);
+        assert_eq!(probe.filter_height(&bake), 1080);
+
+        let probe = rotated_probe(r#"{"streams": [{"width": 1920, "height": 1080, "tags": {"rotate": "90"}}]}"#);
+        assert_eq!(probe.rotation, 90);
+        assert_eq!(probe.filter_height(&preserve), 1080);
+        assert_eq!(probe.filter_height(&bake), 1920);
+        assert_eq!(probe.display_aspect_ratio(), 1080.0 / 1920.0);
+
+        let probe = rotated_probe(r#"{"streams": [{"width": 1920, "height": 1080}]}"#);
+        assert_eq!(probe.rotation, 0);
+        assert_eq!(probe.filter_height(&bake), 1080);
+        assert_eq!(probe.display_aspect_ratio(), 1920.0 / 1080.0);
+    }
+
+    #[test]
+    fn gpu_session_limits_are_per_cuda_device() {
+        let config = Config { max_gpu_sessions: Some(2), cuda_devices: vec![0, 1, 1], ..Config::default() };
+        let limits = gpu_session_limits(&config);
+        assert_eq!(limits.len(), 2);
+        assert!([Some(0), Some(1)].iter().all(|cuda_device| limits[cuda_device].max_running == 2));
+
+        let config = Config { max_gpu_sessions: Some(3), ..Config::default() };
+        let limits = gpu_session_limits(&config);
+        assert_eq!(limits.len(), 1);
+        assert_eq!(limits[&None].max_running, 3);
+    }
+
+    #[test]
+    fn append_writer_keeps_concurrent_lines_intact() {
+        const THREADS: usize = 8;
+        const LINES: usize = 500;
+        let dir = test_dir("append");
+        let path = dir.join("appended.log");
+        // long enough that interleaved writes would show up as broken lines
+        let padding = "x".repeat(300);
+        {
+            let writer = Arc::new(AppendWriter::open(&path).unwrap());
+            let threads: Vec<_> = (0..THREADS).map(|thread_index| {
+                let (writer, padding) = (writer.clone(), padding.clone());
+                thread::spawn(move || {
+                    for line_index in 0..LINES {
+                        writer.append_line(format!("{} {} {}", thread_index, line_index, padding));
+                    }
+                })
+            }).collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), THREADS * LINES);
+        let mut next_line_index = [0; THREADS];
+        for line in lines {
+            let mut fields = line.splitn(3, ' ');
+            let thread_index: usize = fields.next().unwrap().parse().unwrap();
+            let line_index: usize = fields.next().unwrap().parse().unwrap();
+            assert_eq!(fields.next(), Some(padding.as_str()));
+            // each thread's lines stay in the order it sent them
+            assert_eq!(line_index, next_line_index[thread_index]);
+            next_line_index[thread_index] += 1;
+        }
+        assert_eq!(next_line_index, [LINES; THREADS]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn renditions_get_distinct_encoding_and_save_paths() {
+        let config = Config::default();
+        let encoding_video_dir = PathBuf::from("/encoding");
+        let video_location_hash = hash_file_location("/videos/movie.avi");
+        let save_path = PathBuf::from("/save/movie.mkv");
+
+        let encoding_1080p = suffixed_encoding_video_path(&encoding_video_dir, &video_location_hash, "1080p", &config);
+        let encoding_720p = suffixed_encoding_video_path(&encoding_video_dir, &video_location_hash, "720p", &config);
+        assert_eq!(encoding_1080p, encoding_video_dir.join(format!("{}.1080p.mkv", video_location_hash)));
+        assert_ne!(encoding_1080p, encoding_720p);
+        assert_ne!(encoding_1080p, encoding_video_dir.join(&video_location_hash).with_extension(&config.output_extension));
+
+        assert_eq!(rendition_file_save_path(&save_path, "1080p"), PathBuf::from("/save/movie.1080p.mkv"));
+        assert_eq!(rendition_file_save_path(&save_path, "720p"), PathBuf::from("/save/movie.720p.mkv"));
+    }
+
+    #[test]
+    fn chapter_segments_get_distinct_encoding_paths() {
+        let config = Config::default();
+        let encoding_video_dir = PathBuf::from("/encoding");
+        let chapters = [
+            Chapter { start_secs: 0.0, end_secs: 60.0, title: Some("Opening".to_string()) },
+            Chapter { start_secs: 60.0, end_secs: 120.0, title: None },
+        ];
+        let segments = chapter_segments(&chapters, Path::new("/save/movie.mkv"), &encoding_video_dir, "hash", &config);
+        assert_eq!(segments, vec![
+            (PathBuf::from("/save/movie - 01 Opening.mkv"), encoding_video_dir.join("hash.1.mkv"), Some((0.0, 60.0))),
+            (PathBuf::from("/save/movie - 02.mkv"), encoding_video_dir.join("hash.2.mkv"), Some((60.0, 120.0))),
+        ]);
+        // nor may a chapter land on the path of the unsplit encode
+        assert!(segments.iter().all(|(_, encoding_video_path, _)| *encoding_video_path != encoding_video_dir.join("hash.mkv")));
+    }
+
+    #[test]
+    fn status_file_stays_whole_under_concurrent_updates() {
+        const THREADS: usize = 8;
+        const UPDATES: usize = 100;
+        let dir = test_dir("status");
+        let path = dir.join("status.json");
+        let status_file = StatusFile::start(&path, None).unwrap();
+        let updating_done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            // a monitor reading along must never see partial JSON
+            let reader = scope.spawn(|| {
+                let mut reads = 0;
+                while !updating_done.load(Ordering::SeqCst) {
+                    let status: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+                    assert_eq!(status["pid"], std::process::id());
+                    reads += 1;
+                }
+                reads
+            });
+            let updaters: Vec<_> = (0..THREADS).map(|thread_index| {
+                let status_file = &status_file;
+                scope.spawn(move || {
+                    for update_index in 0..UPDATES {
+                        let current_file = PathBuf::from(format!("{}-{}.mkv", thread_index, update_index));
+                        status_file.update(thread_index * UPDATES + update_index, Some(&current_file)).unwrap();
+                    }
+                })
+            }).collect();
+            for updater in updaters {
+                updater.join().unwrap();
+            }
+            updating_done.store(true, Ordering::SeqCst);
+            assert!(reader.join().unwrap() > 0);
+        });
+        status_file.finish().unwrap();
+
+        let status: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(status["finished"], true);
+        assert_eq!(files_under(&dir), vec![path]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn review_list_keeps_every_concurrent_add() {
+        const THREADS: usize = 8;
+        const ADDS: usize = 50;
+        let dir = test_dir("review-list");
+        let path = dir.join("review.tsv");
+        let review_list = ReviewList::load(&path).unwrap();
+        thread::scope(|scope| {
+            for thread_index in 0..THREADS {
+                let (review_list, dir) = (&review_list, &dir);
+                scope.spawn(move || {
+                    for add_index in 0..ADDS {
+                        let video_path = dir.join(format!("{}-{}.mkv", thread_index, add_index));
+                        assert!(review_list.add(&video_path, ReviewReason::Manual).unwrap());
+                    }
+                });
+            }
+        });
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), THREADS * ADDS);
+        assert!(content.lines().all(|line| line.split('\t').count() == 3));
+        let reloaded = ReviewList::load(&path).unwrap();
+        for thread_index in 0..THREADS {
+            for add_index in 0..ADDS {
+                assert!(reloaded.contains(dir.join(format!("{}-{}.mkv", thread_index, add_index))));
+            }
+        }
+        assert_eq!(files_under(&dir), vec![path]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn multipart_base_path_matches_grouping() {
+        let config = Config::default();
+        let multipart_pattern = Regex::new(&config.multipart_pattern).unwrap();
+        // matches twice, only the first ".partN" numbers the parts
+        let parts = vec![PathBuf::from("/videos/show.part1.part9.mkv"), PathBuf::from("/videos/show.part2.part9.mkv")];
+        let (video_paths, multiparts) = group_multipart_videos(parts.clone(), &multipart_pattern);
+        assert_eq!(video_paths, vec![parts[0].clone()]);
+        assert_eq!(multiparts[&parts[0]], parts);
+        assert_eq!(multipart_base_path(&parts[0], &config.multipart_pattern).unwrap(), PathBuf::from("/videos/show.part9.mkv"));
+        assert_eq!(split_multipart_filename("show.part2.part9.mkv", &multipart_pattern), Some(("show.part9.mkv".to_string(), 2)));
+        assert_eq!(split_multipart_filename("show.mkv", &multipart_pattern), None);
+    }
+}